@@ -0,0 +1,7 @@
+extern crate prost_build;
+
+// Generates the types for the protobuf encoding of broadcasts, so the encoder cannot drift from the schema
+fn main() {
+    prost_build::compile_protos(&["proto/aggregator/broadcast/v1/broadcast.proto"], &["proto/"])
+        .expect("Could not compile the broadcast protobuf schema");
+}
\ No newline at end of file
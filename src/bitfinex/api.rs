@@ -1,8 +1,22 @@
+use std::str::FromStr;
+
 pub type ChannelId = i32;
 pub type OrderId = i64;
 pub type Timestamp = f64;
 pub type Amount = f64;
 pub type Price = f64;
+pub type Count = i32;
+pub type SequenceNumber = i64;
+pub type Checksum = i32;
+
+// Flags for the conf event
+// SEQ_ALL adds a sequence number to every message and OB_CHECKSUM sends a checksum of each book after every update
+pub const SEQ_ALL: i32 = 65536;
+pub const OB_CHECKSUM: i32 = 131072;
+
+// Raw books hold individual orders as (OrderId, Price, Amount) and aggregated books hold price levels as (Price, Count, Amount)
+// The two can't be told apart by their shape, so entries are read according to the precision of their channel
+pub type BookEntry = [::serde_json::Number; 3];
 
 #[derive(Debug, Deserialize)]
 #[serde(untagged)]
@@ -13,6 +27,13 @@ pub enum Response {
         #[serde(skip)]
         platform: ::serde::de::IgnoredAny
     },
+    // Responses are told apart by their fields, so fields that are not read are still required
+    ConfConfirmation {
+        #[serde(rename = "event")]
+        _event: ::serde::de::IgnoredAny,
+        status: String,
+        flags: i32
+    },
     SubscribeConfirmation {
         event: String,
         channel: String,
@@ -27,6 +48,14 @@ pub enum Response {
         #[serde(rename = "len")]
         length: Option<String>,
     },
+    UnsubscribeConfirmation {
+        #[serde(rename = "event")]
+        _event: ::serde::de::IgnoredAny,
+        #[serde(rename = "status")]
+        _status: ::serde::de::IgnoredAny,
+        #[serde(rename = "chanId")]
+        channel_id: ChannelId
+    },
     SubscribeError {
         event: String,
         channel: String,
@@ -41,30 +70,78 @@ pub enum Response {
         #[serde(rename = "len")]
         length: String,
     },
-    Heartbeat(ChannelId, String), // The string here is always "hb"
-    InitialTrade(ChannelId, Vec<(OrderId, Timestamp, Amount, Price)>),
-    Trade(ChannelId, TradeUpdateType, (OrderId, Timestamp, Amount, Price)),
-    InitialOrderbook(ChannelId, Vec<(OrderId, Price, Amount)>),
-    OrderbookUpdate(ChannelId, (OrderId, Price, Amount)),
+    // Channel messages end with the sequence number added by SEQ_ALL
+    Heartbeat(ChannelId, HeartbeatTag, SequenceNumber),
+    Checksum(ChannelId, ChecksumTag, Checksum, SequenceNumber),
+    InitialTrade(ChannelId, Vec<(OrderId, Timestamp, Amount, Price)>, SequenceNumber),
+    Trade(ChannelId, TradeUpdateType, (OrderId, Timestamp, Amount, Price), SequenceNumber),
+    InitialOrderbook(ChannelId, Vec<BookEntry>, SequenceNumber),
+    OrderbookUpdate(ChannelId, BookEntry, SequenceNumber),
+}
+
+// The strings that mark heartbeats and checksums, which would otherwise look like book updates
+#[derive(Debug, Deserialize)]
+pub enum HeartbeatTag {
+    #[serde(rename = "hb")]
+    Heartbeat
+}
+
+#[derive(Debug, Deserialize)]
+pub enum ChecksumTag {
+    #[serde(rename = "cs")]
+    Checksum
+}
+
+impl Response {
+    pub fn sequence(&self) -> Option<(ChannelId, SequenceNumber)> {
+        match *self {
+            Response::Heartbeat(channel_id, _, sequence) |
+            Response::Checksum(channel_id, _, _, sequence) |
+            Response::InitialTrade(channel_id, _, sequence) |
+            Response::Trade(channel_id, _, _, sequence) |
+            Response::InitialOrderbook(channel_id, _, sequence) |
+            Response::OrderbookUpdate(channel_id, _, sequence) => Some((channel_id, sequence)),
+            _ => None
+        }
+    }
+}
+
+pub fn order_entry(entry: &BookEntry) -> Option<(OrderId, Price, Amount)> {
+    Some((entry[0].as_i64()?, entry[1].as_f64()?, entry[2].as_f64()?))
+}
+
+pub fn level_entry(entry: &BookEntry) -> Option<(Price, Count, Amount)> {
+    Some((entry[0].as_f64()?, entry[1].as_i64()? as Count, entry[2].as_f64()?))
 }
 
 #[derive(Debug, Serialize)]
 #[serde(untagged)]
 pub enum Request {
+    Configure {
+        event: String,
+        flags: i32
+    },
+    LeaveQueue {
+        event: String,
+        #[serde(rename = "chanId")]
+        channel_id: ChannelId
+    },
+    // Precision, frequency and length only apply to book channels
     JoinQueue {
         event: String,
         channel: String,
         symbol: String,
-        #[serde(rename = "prec")]
-        precision: Precision,
-        #[serde(rename = "freq")]
-        frequency: Frequency,
-        #[serde(rename = "len")]
-        length: String
+        #[serde(rename = "prec", skip_serializing_if = "Option::is_none")]
+        precision: Option<Precision>,
+        #[serde(rename = "freq", skip_serializing_if = "Option::is_none")]
+        frequency: Option<Frequency>,
+        #[serde(rename = "len", skip_serializing_if = "Option::is_none")]
+        length: Option<String>
     }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+// R0 books hold individual orders, P0 to P3 books aggregate orders into price levels with decreasing precision
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
 pub enum Precision {
     R0,
     P0,
@@ -73,14 +150,46 @@ pub enum Precision {
     P3
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+impl FromStr for Precision {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_uppercase().as_str() {
+            "R0" => Ok(Precision::R0),
+            "P0" => Ok(Precision::P0),
+            "P1" => Ok(Precision::P1),
+            "P2" => Ok(Precision::P2),
+            "P3" => Ok(Precision::P3),
+            _ => Err("expected one of R0, P0, P1, P2 or P3".to_string())
+        }
+    }
+}
+
+// F0 sends updates in real time, F1 batches them every two seconds
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq)]
 pub enum Frequency {
     F0,
     F1
 }
 
+impl FromStr for Frequency {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_uppercase().as_str() {
+            "F0" => Ok(Frequency::F0),
+            "F1" => Ok(Frequency::F1),
+            _ => Err("expected F0 or F1".to_string())
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub enum TradeUpdateType {
-    WithTradeId,
-    WithoutTradeId
+    // Sent as soon as a trade executes
+    #[serde(rename = "te")]
+    Executed,
+    // Repeats an executed trade once it has been recorded
+    #[serde(rename = "tu")]
+    Updated
 }
\ No newline at end of file
@@ -1,17 +1,181 @@
-mod api;
+pub mod api;
 
 use self::api::*;
-use broadcast_api::{Broadcast, BroadcastType};
+use broadcast_api::{self as broadcast, Broadcast, BroadcastType, Side};
 use super::domain::*;
-use consumer::{self, handler::HandlerCore, MarketHandler, ConnectionFactory};
+use config::{BookConfig, ExchangeConfig};
+use consumer::{self, error::*, handler::{HandlerCore, HandlerContext}, MarketHandler, ConnectionFactory};
 use ws;
-use std::{collections::HashMap};
+use std::{collections::{BTreeMap, HashMap, HashSet}};
+
+type ChannelsMap = HashMap<ChannelId, Channel>;
+
+// Number of levels on each side of the book covered by checksums
+const CHECKSUM_DEPTH: usize = 25;
+
+// Bitfinex uses its own three letter codes for some assets
+const ASSET_CODES: &AssetCodes = &[
+    ("DASH", "DSH"),
+    ("IOTA", "IOT"),
+    ("QTUM", "QTM"),
+    ("USDT", "UST"),
+    ("DATA", "DAT"),
+    ("MANA", "MNA"),
+    ("YOYOW", "YYW")
+];
+
+struct Channel {
+    name: String,
+    pair: CurrencyPair,
+    // Only book channels have a precision
+    precision: Option<Precision>
+}
+
+enum Book {
+    Raw(RawBook),
+    Aggregated(AggregatedBook)
+}
+
+impl Book {
+    fn checksum(&self) -> Checksum {
+        match *self {
+            Book::Raw(ref book) => book.checksum(),
+            Book::Aggregated(ref book) => book.checksum()
+        }
+    }
+}
+
+// The amount is kept as it was received, since checksums are calculated from the exchange's representation
+struct RawOrder {
+    price: broadcast::Price,
+    amount: broadcast::Volume,
+    raw_amount: Amount
+}
+
+// Raw books report individual orders, which are aggregated into price levels before being broadcast
+#[derive(Default)]
+struct RawBook {
+    orders: HashMap<OrderId, RawOrder>,
+    levels: OrderBook
+}
+
+impl RawBook {
+    // Positive amounts are bids and negative amounts are asks
+    fn adjust_level(&mut self, price: broadcast::Price, amount: broadcast::Volume, adding: bool) {
+        let change = if adding { amount.abs() } else { -amount.abs() };
+        if amount > 0 {
+            let volume = self.levels.bid(price) + change;
+            self.levels.set_bid(price, volume);
+        } else {
+            let volume = self.levels.ask(price) + change;
+            self.levels.set_ask(price, volume);
+        }
+    }
+
+    fn add_order(&mut self, order_id: OrderId, price: Price, amount: Amount) {
+        let order = RawOrder {
+            price: consumer::standardise_value(price),
+            amount: consumer::standardise_value(amount),
+            raw_amount: amount
+        };
+        self.adjust_level(order.price, order.amount, true);
+        self.orders.insert(order_id, order);
+    }
+
+    // Volumes of individual orders are broadcast as positive amounts, like price levels
+    fn order_list(&self) -> OrderList {
+        let mut list = OrderList::new();
+        for (&order_id, order) in &self.orders {
+            list.insert(side(order.amount), (order_id, order.price, order.amount.abs()));
+        }
+        list
+    }
+
+    fn remove_order(&mut self, order_id: OrderId) -> Option<(broadcast::Price, broadcast::Volume)> {
+        let removed = self.orders.remove(&order_id);
+        removed.map(|order| {
+            self.adjust_level(order.price, order.amount, false);
+            (order.price, order.amount)
+        })
+    }
+
+    // Raw books identify each entry by order ID, with orders at the same price ranked by ID
+    fn checksum(&self) -> Checksum {
+        let mut bids: Vec<(&OrderId, &RawOrder)> = self.orders.iter().filter(|&(_, order)| order.amount > 0).collect();
+        let mut asks: Vec<(&OrderId, &RawOrder)> = self.orders.iter().filter(|&(_, order)| order.amount < 0).collect();
+        bids.sort_unstable_by_key(|&(order_id, order)| (-order.price, *order_id));
+        asks.sort_unstable_by_key(|&(order_id, order)| (order.price, *order_id));
 
-type ChannelsMap = HashMap<i32, CurrencyPair>;
+        let entries = |orders: Vec<(&OrderId, &RawOrder)>| orders.into_iter()
+            .map(|(order_id, order)| (order_id.to_string(), order.raw_amount))
+            .collect();
+
+        checksum(entries(bids), entries(asks))
+    }
+}
+
+// Aggregated books report price levels, keyed here by standardised price
+// The price and amount are kept as they were received for checksums
+#[derive(Default)]
+struct AggregatedBook {
+    levels: BTreeMap<broadcast::Price, (Price, Amount)>
+}
+
+impl AggregatedBook {
+    // Aggregated books identify each entry by price
+    fn checksum(&self) -> Checksum {
+        let bids = self.levels.values().rev()
+            .filter(|&&(_, amount)| amount > 0.0)
+            .map(|&(price, amount)| (format_number(price), amount))
+            .collect();
+        let asks = self.levels.values()
+            .filter(|&&(_, amount)| amount < 0.0)
+            .map(|&(price, amount)| (format_number(price), amount))
+            .collect();
+
+        checksum(bids, asks)
+    }
+}
+
+// CRC32 of the top entries on each side, interleaved as bid key, bid amount, ask key, ask amount
+// Entries must be ordered from the best price outwards
+// https://docs.bitfinex.com/docs/ws-websocket-checksum
+fn checksum(bids: Vec<(String, Amount)>, asks: Vec<(String, Amount)>) -> Checksum {
+    let mut values = vec!();
+    for i in 0..CHECKSUM_DEPTH {
+        for side in &[&bids, &asks] {
+            if let Some(&(ref key, amount)) = side.get(i) {
+                values.push(key.clone());
+                values.push(format_number(amount));
+            }
+        }
+    }
+
+    ::crc::crc32::checksum_ieee(values.join(":").as_bytes()) as Checksum
+}
+
+fn side(amount: broadcast::Volume) -> Side {
+    if amount > 0 { Side::Bid } else { Side::Ask }
+}
+
+// Formats a number the same way as JavaScript, which is what the exchange uses when calculating checksums
+fn format_number(value: f64) -> String {
+    if value != 0.0 && value.abs() < 1e-6 {
+        format!("{:e}", value)
+    } else {
+        format!("{}", value)
+    }
+}
 
 pub struct BitfinexHandler {
     inner: HandlerCore,
     channels: ChannelsMap,
+    books: HashMap<ChannelId, Book>,
+    book_config: BookConfig,
+    // Channels whose messages are ignored while waiting for them to be unsubscribed
+    unsubscribing: HashSet<ChannelId>,
+    sequence: Option<SequenceNumber>,
+    symbols: SymbolTable,
     pairs: Vec<CurrencyPair>
 }
 
@@ -22,50 +186,62 @@ impl ::ws::Handler for BitfinexHandler {
     generic_on_message!(Response);
 
     generic_on_close!(Exchange::Bitfinex);
+
+    generic_on_timeout!(Exchange::Bitfinex);
 }
 
 impl MarketHandler for BitfinexHandler {
 
-    fn get_requests(pairs: &[CurrencyPair]) -> Vec<String> {
-        pairs.iter().flat_map(|pair| { vec!(
-            Request::JoinQueue {
-                event: "subscribe".to_string(),
-                channel: "book".to_string(),
-                symbol: Self::stringify_pair(pair),
-                precision: Precision::R0,
-                frequency: Frequency::F0,
-                length: 100.to_string()
-            },
-            Request::JoinQueue {
-                event: "subscribe".to_string(),
-                channel: "trades".to_string(),
-                symbol: Self::stringify_pair(pair),
-                precision: Precision::R0,
-                frequency: Frequency::F0,
-                length: 100.to_string()
-            }
-        )}).map(|req| ::serde_json::to_string(&req).unwrap()).collect()
+    // Configuration must come first so that it applies to every channel
+    fn get_requests(&self) -> Vec<String> {
+        let configure = Request::Configure {
+            event: "conf".to_string(),
+            flags: SEQ_ALL | OB_CHECKSUM
+        };
+
+        let subscriptions = self.pairs.iter().flat_map(|pair| self.pair_requests(pair));
+
+        ::std::iter::once(configure).chain(subscriptions)
+            .map(|req| ::serde_json::to_string(&req).unwrap()).collect()
     }
 
+    // Asset codes are joined directly, unless either is longer than three letters, when a colon separates them
+    // For example tXRPBTC, but tDOGE:USD and tTESTBTC:TESTUSD
     fn stringify_pair(pair: &CurrencyPair) -> String {
-        match *pair {
-            CurrencyPair::XRPBTC => "tXRPBTC"
-        }.to_string()
+        let base = exchange_asset_code(&pair.base, ASSET_CODES);
+        let quote = exchange_asset_code(&pair.quote, ASSET_CODES);
+        if base.len() > 3 || quote.len() > 3 {
+            format!("t{}:{}", base, quote)
+        } else {
+            format!("t{}{}", base, quote)
+        }
     }
 }
 
 pub struct BitfinexFactory {
-    broadcast_tx: ws::Sender,
-    pairs: Vec<CurrencyPair>
+    context: HandlerContext,
+    pairs: Vec<CurrencyPair>,
+    book_config: BookConfig
 }
 
 impl ConnectionFactory for BitfinexFactory {
-    fn new(broadcast_tx: ws::Sender, pairs: Vec<CurrencyPair>) -> Self {
-        Self { broadcast_tx, pairs }
+    const EXCHANGE: Exchange = Exchange::Bitfinex;
+
+    fn new(context: HandlerContext, pairs: Vec<CurrencyPair>, exchange: &ExchangeConfig) -> Self {
+        Self { context, pairs, book_config: exchange.books.clone() }
     }
 
-    fn get_connect_addr() -> ::url::Url {
-        ::url::Url::parse(dotenv!("BITFINEX_ADDR")).unwrap()
+    fn handler(&self, inner: HandlerCore) -> Self::Handler {
+        Self::Handler {
+            inner,
+            symbols: SymbolTable::new(&self.pairs, BitfinexHandler::stringify_pair),
+            pairs: self.pairs.clone(),
+            channels: HashMap::new(),
+            books: HashMap::new(),
+            book_config: self.book_config.clone(),
+            unsubscribing: HashSet::new(),
+            sequence: None
+        }
     }
 }
 
@@ -73,82 +249,303 @@ impl ws::Factory for BitfinexFactory {
     type Handler = BitfinexHandler;
 
     fn connection_made(&mut self, sender: ws::Sender) -> Self::Handler {
-        Self::Handler {
-            inner: HandlerCore::new(self.broadcast_tx.clone(), sender),
-            pairs: self.pairs.clone(),
-            channels: HashMap::new()
-        }
+        self.handler(HandlerCore::new(self.context.clone(), sender, Self::EXCHANGE))
     }
 }
 
 impl BitfinexHandler {
-    fn handle_response(&mut self, response: Response) -> BroadcastType {
+    fn handle_response(&mut self, response: Response) -> Result<BroadcastType> {
+        if let Some((channel_id, sequence)) = response.sequence() {
+            if let Some(last) = self.sequence {
+                if sequence != last + 1 {
+                    warn!("{} sequence jumped from {} to {}, resubscribing to books", Exchange::Bitfinex, last, sequence);
+                    let invalidated = self.invalidate_books();
+                    if let Err(e) = self.inner.broadcast(BroadcastType::Many(invalidated)) {
+                        error!("Could not broadcast book invalidations: {}", e);
+                    }
+                }
+            }
+            self.sequence = Some(sequence);
+
+            if self.unsubscribing.contains(&channel_id) {
+                return Ok(BroadcastType::None);
+            }
+        }
+
+        let mapped = self.map_response(response);
+
+        // Nothing on a channel we don't know about can be used, so stop the exchange sending it
+        if let Err(ref e) = mapped {
+            if let ErrorKind::UnknownChannel(_, channel_id) = *e.kind() {
+                self.leave_channel(channel_id as ChannelId);
+            }
+        }
+
+        mapped
+    }
+
+    fn map_response(&mut self, response: Response) -> Result<BroadcastType> {
         match response {
-            // TODO: remove pair code duplication
-            Response::OrderbookUpdate(channel_id, (_, price, amount)) => {
-                let pair = self.channels.get(&channel_id).expect(
-                    &format!("Could not find channel ID {}", channel_id));
-                map_orderbook_update(*pair, price, amount)
+            Response::OrderbookUpdate(channel_id, entry, _) => {
+                let mapped = {
+                    let channel = channel(&self.channels, channel_id)?;
+                    match book_for(&mut self.books, channel_id, channel) {
+                        Book::Raw(book) => order_entry(&entry).map(|(order_id, price, amount)|
+                            map_orderbook_update(channel.pair.clone(), book, order_id, price, amount)),
+                        Book::Aggregated(book) => level_entry(&entry).map(|(price, count, amount)|
+                            map_level_update(channel.pair.clone(), book, price, count, amount))
+                    }
+                };
+
+                match mapped {
+                    Some(broadcasts) => Ok(broadcasts),
+                    None => Err(self.invalid_book_entry(channel_id, &entry))
+                }
+            },
+            Response::Trade(channel_id, TradeUpdateType::Executed, trades, _) => {
+                let pair = &channel(&self.channels, channel_id)?.pair;
+                Ok(map_trade(pair.clone(), trades))
+            },
+            Response::Checksum(channel_id, _, checksum, _) => {
+                let local = self.books.get(&channel_id).map(Book::checksum);
+                if local == Some(checksum) {
+                    Ok(BroadcastType::None)
+                } else {
+                    warn!("{} checksum mismatch on channel ID {}: expected {}, calculated {:?}",
+                          Exchange::Bitfinex, channel_id, checksum, local);
+                    match self.invalidate_book(channel_id) {
+                        Some(broadcast) => Ok(BroadcastType::One(broadcast)),
+                        None => Ok(BroadcastType::None)
+                    }
+                }
             },
-            Response::Trade(channel_id, _trade_update_type, trades) => {
-                let pair = self.channels.get(&channel_id).expect(
-                    &format!("Could not find channel ID {}", channel_id));
-                map_trade(*pair, trades)
+            Response::SubscribeConfirmation { channel_id, channel, symbol, precision, .. } => {
+                let pair_code = match map_pair_code(&self.symbols, &symbol) {
+                    Ok(pair_code) => pair_code,
+                    Err(e) => {
+                        self.leave_channel(channel_id);
+                        return Err(e);
+                    }
+                };
+                debug!("{} pair code {:?} maps to {} channel ID {}", Exchange::Bitfinex, pair_code, channel, channel_id);
+                self.channels.insert(channel_id, Channel { name: channel, pair: pair_code.clone(), precision });
+
+                let subscribed = self.channels.values().filter(|channel| channel.pair == pair_code).count();
+                if subscribed == self.pair_requests(&pair_code).len() {
+                    self.inner.pair_subscribed(&pair_code);
+                }
+                Ok(BroadcastType::None)
             },
-            Response::SubscribeConfirmation { channel_id, symbol, .. } => {
-                let pair_code = map_pair_code(&symbol);
-                debug!("{} pair code {:?} maps to channel ID {}", Exchange::Bitfinex, pair_code, channel_id);
-                self.channels.insert(channel_id, pair_code);
-                BroadcastType::None
+            Response::UnsubscribeConfirmation { channel_id, .. } => {
+                self.unsubscribing.remove(&channel_id);
+                Ok(BroadcastType::None)
             },
-            Response::InitialOrderbook(channel_id, orders) => {
-                let pair = self.channels.get(&channel_id).expect(
-                    &format!("Could not find channel ID {}", channel_id));
-                map_initial_orderbook(*pair, orders)
+            Response::ConfConfirmation { status, flags, .. } => {
+                debug!("{} configuration flags {} set with status {}", Exchange::Bitfinex, flags, status);
+                Ok(BroadcastType::None)
             },
-            Response::InitialTrade(channel_id, trades) => {
-                let pair = self.channels.get(&channel_id).expect(
-                    &format!("Could not find channel ID {}", channel_id));
-                map_initial_trades(*pair, trades)
+            Response::InitialOrderbook(channel_id, entries, _) => {
+                let channel = channel(&self.channels, channel_id)?;
+                Ok(map_initial_book(channel.pair.clone(), book_for(&mut self.books, channel_id, channel), entries))
+            },
+            // An empty book snapshot can't be told apart from an empty trades snapshot
+            Response::InitialTrade(channel_id, trades, _) => {
+                let channel = channel(&self.channels, channel_id)?;
+                if channel.name == "book" {
+                    Ok(map_initial_book(channel.pair.clone(), book_for(&mut self.books, channel_id, channel), vec!()))
+                } else {
+                    Ok(map_initial_trades(channel.pair.clone(), trades))
+                }
+            }
+            _ => Ok(BroadcastType::None)
+        }
+    }
+
+    // Unsubscribes from a channel that can't be handled, ignoring its messages in the meantime
+    fn leave_channel(&mut self, channel_id: ChannelId) {
+        if !self.unsubscribing.insert(channel_id) {
+            return;
+        }
+
+        let request = Request::LeaveQueue { event: "unsubscribe".to_string(), channel_id };
+        let requests = vec!(::serde_json::to_string(&request).unwrap());
+
+        info!("Leaving {} channel ID {}", Exchange::Bitfinex, channel_id);
+        if let Err(e) = self.inner.send_upstream(&requests) {
+            error!("Could not leave {} channel ID {}: {}", Exchange::Bitfinex, channel_id, e);
+        }
+    }
+
+    // The book can't be kept in step once an update can't be applied, so it is fetched again from scratch
+    fn invalid_book_entry(&mut self, channel_id: ChannelId, entry: &BookEntry) -> Error {
+        if let Some(broadcast) = self.invalidate_book(channel_id) {
+            if let Err(e) = self.inner.broadcast(BroadcastType::One(broadcast)) {
+                error!("Could not broadcast book invalidation: {}", e);
             }
-            _ => BroadcastType::None
         }
+
+        ErrorKind::ProtocolViolation(Exchange::Bitfinex,
+            format!("book entry {:?} on channel ID {} does not match the precision of the channel", entry, channel_id)).into()
+    }
+
+    fn invalidate_books(&mut self) -> Vec<Broadcast> {
+        let book_channels: Vec<ChannelId> = self.channels.iter()
+            .filter(|&(_, channel)| channel.name == "book")
+            .map(|(&channel_id, _)| channel_id)
+            .collect();
+
+        book_channels.into_iter().filter_map(|channel_id| self.invalidate_book(channel_id)).collect()
+    }
+
+    // Drops a book that has drifted from the exchange and subscribes again for a new snapshot
+    fn invalidate_book(&mut self, channel_id: ChannelId) -> Option<Broadcast> {
+        let channel = self.channels.remove(&channel_id)?;
+        self.books.remove(&channel_id);
+        self.unsubscribing.insert(channel_id);
+        self.inner.pair_unsubscribed(&channel.pair);
+
+        let requests: Vec<String> = [
+            Request::LeaveQueue { event: "unsubscribe".to_string(), channel_id },
+            self.book_request(&channel.pair)
+        ].iter().map(|req| ::serde_json::to_string(req).unwrap()).collect();
+
+        if let Err(e) = self.inner.send_upstream(&requests) {
+            error!("Could not resubscribe to {} {} book: {}", Exchange::Bitfinex, channel.pair, e);
+        }
+
+        Some(Broadcast::OrderbookInvalidated {
+            source: Exchange::Bitfinex,
+            pair: channel.pair,
+            ts: self.inner.timestamp()
+        })
+    }
+
+    // Every channel subscribed to for a pair, which is only confirmed once all of them are
+    fn pair_requests(&self, pair: &CurrencyPair) -> Vec<Request> {
+        vec!(self.book_request(pair), trades_request(pair))
+    }
+
+    fn book_request(&self, pair: &CurrencyPair) -> Request {
+        let subscription = self.book_config.subscription(pair);
+        Request::JoinQueue {
+            event: "subscribe".to_string(),
+            channel: "book".to_string(),
+            symbol: Self::stringify_pair(pair),
+            precision: Some(subscription.precision),
+            frequency: Some(subscription.frequency),
+            length: Some(subscription.length.to_string())
+        }
+    }
+}
+
+fn trades_request(pair: &CurrencyPair) -> Request {
+    Request::JoinQueue {
+        event: "subscribe".to_string(),
+        channel: "trades".to_string(),
+        symbol: BitfinexHandler::stringify_pair(pair),
+        precision: None,
+        frequency: None,
+        length: None
     }
 }
 
+// Books are created to match the precision the channel was subscribed with
+fn book_for<'a>(books: &'a mut HashMap<ChannelId, Book>, channel_id: ChannelId, channel: &Channel) -> &'a mut Book {
+    books.entry(channel_id).or_insert_with(|| match channel.precision {
+        Some(Precision::R0) | None => Book::Raw(RawBook::default()),
+        Some(_) => Book::Aggregated(AggregatedBook::default())
+    })
+}
+
+fn channel(channels: &ChannelsMap, channel_id: ChannelId) -> Result<&Channel> {
+    channels.get(&channel_id).ok_or_else(|| ErrorKind::UnknownChannel(Exchange::Bitfinex, channel_id as i64).into())
+}
 
 // Pairs list: https://api.bitfinex.com/v1/symbols
-fn map_pair_code(pair_code: &str) -> CurrencyPair {
-    match pair_code {
-        "tXRPBTC" => CurrencyPair::XRPBTC,
-        _ => panic!("Could not map pair code {}", pair_code)
+fn map_pair_code(symbols: &SymbolTable, pair_code: &str) -> Result<CurrencyPair> {
+    match symbols.pair(pair_code) {
+        Some(pair) => Ok(pair.clone()),
+        None => Err(ErrorKind::UnknownPair(Exchange::Bitfinex, pair_code.to_string()).into())
     }
 }
 
-fn map_orderbook_update(pair: CurrencyPair, price: f64, amount: f64) -> BroadcastType {
+// An order with a price of zero has been removed from the book
+// Otherwise the order is new or has been modified, so it replaces any previous state of the order
+// Both the price levels and the order itself are broadcast
+fn map_orderbook_update(pair: CurrencyPair, book: &mut RawBook, order_id: OrderId, price: Price, amount: Amount) -> BroadcastType {
     let standardised_price = consumer::standardise_value(price);
     let standardised_amount = consumer::standardise_value(amount);
 
-    let (mut bids, mut asks) = (vec!(), vec!());
-    if standardised_amount > 0 {
-        bids.push((standardised_price, standardised_amount));
-    } else {
-        asks.push((standardised_price, standardised_amount.abs()));
+    // Price levels touched by this order as (is bid, price)
+    let mut changed_levels = vec!();
+
+    let previous = book.remove_order(order_id);
+    if let Some((old_price, old_amount)) = previous {
+        changed_levels.push((old_amount > 0, old_price));
+    }
+    if standardised_price != 0 {
+        book.add_order(order_id, price, amount);
+        changed_levels.push((standardised_amount > 0, standardised_price));
     }
+    changed_levels.dedup();
 
-    let broadcast = if standardised_price == 0 {
-        Broadcast::OrderbookRemove {
+    let order_broadcast = match (previous, standardised_price != 0) {
+        (None, true) => Some(Broadcast::OrderAdded {
             source: Exchange::Bitfinex,
-            pair, bids, asks
-        }
-    } else {
-        Broadcast::OrderbookUpdate {
+            pair: pair.clone(),
+            side: side(standardised_amount),
+            order: (order_id, standardised_price, standardised_amount.abs())
+        }),
+        (Some(_), true) => Some(Broadcast::OrderModified {
             source: Exchange::Bitfinex,
-            pair, bids, asks
-        }
+            pair: pair.clone(),
+            side: side(standardised_amount),
+            order: (order_id, standardised_price, standardised_amount.abs())
+        }),
+        (Some((old_price, old_amount)), false) => Some(Broadcast::OrderCancelled {
+            source: Exchange::Bitfinex,
+            pair: pair.clone(),
+            side: side(old_amount),
+            order: (order_id, old_price, old_amount.abs())
+        }),
+        // Removal of an order that was never in the book
+        (None, false) => None
     };
 
-    BroadcastType::One(broadcast)
+    let (mut updated_bids, mut updated_asks, mut removed_bids, mut removed_asks) = (vec!(), vec!(), vec!(), vec!());
+    for (is_bid, price) in changed_levels {
+        let volume = if is_bid { book.levels.bid(price) } else { book.levels.ask(price) };
+        match (is_bid, volume == 0) {
+            (true, true) => removed_bids.push((price, volume)),
+            (true, false) => updated_bids.push((price, volume)),
+            (false, true) => removed_asks.push((price, volume)),
+            (false, false) => updated_asks.push((price, volume))
+        }
+    }
+
+    let mut broadcasts = vec!();
+
+    if !removed_bids.is_empty() || !removed_asks.is_empty() {
+        broadcasts.push(Broadcast::OrderbookRemove {
+            source: Exchange::Bitfinex,
+            pair: pair.clone(),
+            bids: removed_bids,
+            asks: removed_asks
+        });
+    }
+
+    if !updated_bids.is_empty() || !updated_asks.is_empty() {
+        broadcasts.push(Broadcast::OrderbookUpdate {
+            source: Exchange::Bitfinex,
+            pair,
+            bids: updated_bids,
+            asks: updated_asks
+        });
+    }
+
+    broadcasts.extend(order_broadcast);
+
+    BroadcastType::Many(broadcasts)
 }
 
 fn map_trade(pair: CurrencyPair, trade: (OrderId, Timestamp, Amount, Price)) -> BroadcastType {
@@ -182,18 +579,56 @@ fn map_initial_trades(pair: CurrencyPair, trades: Vec<(OrderId, Timestamp, Amoun
     BroadcastType::One(broadcast)
 }
 
-fn map_initial_orderbook(pair: CurrencyPair, orders: Vec<(OrderId, Price, Amount)>) -> BroadcastType {
-    let (mut bids, mut asks) = (vec!(), vec!());
+fn map_initial_book(pair: CurrencyPair, book: &mut Book, entries: Vec<BookEntry>) -> BroadcastType {
+    match *book {
+        Book::Raw(ref mut book) => {
+            let orders = entries.iter().filter_map(order_entry).collect();
+            map_initial_orderbook(pair, book, orders)
+        },
+        Book::Aggregated(ref mut book) => {
+            let levels = entries.iter().filter_map(level_entry).collect();
+            map_initial_levels(pair, book, levels)
+        }
+    }
+}
+
+fn map_initial_orderbook(pair: CurrencyPair, book: &mut RawBook, orders: Vec<(OrderId, Price, Amount)>) -> BroadcastType {
+    *book = RawBook::default();
     for order in orders {
-        let (_order_id, price, amount) = order;
+        let (order_id, price, amount) = order;
+        book.add_order(order_id, price, amount);
+    }
+
+    let (bids, asks) = book.levels.top(usize::MAX);
+    let (order_bids, order_asks) = book.order_list().ranked();
 
+    BroadcastType::Many(vec!(
+        Broadcast::OrderbookSnapshot {
+            source: Exchange::Bitfinex,
+            pair: pair.clone(),
+            bids, asks
+        },
+        Broadcast::OrderSnapshot {
+            source: Exchange::Bitfinex,
+            pair,
+            bids: order_bids,
+            asks: order_asks
+        }
+    ))
+}
+
+fn map_initial_levels(pair: CurrencyPair, book: &mut AggregatedBook, levels: Vec<(Price, Count, Amount)>) -> BroadcastType {
+    *book = AggregatedBook::default();
+    let (mut bids, mut asks) = (vec!(), vec!());
+    for (price, _count, amount) in levels {
         let standardised_price = consumer::standardise_value(price);
         let standardised_amount = consumer::standardise_value(amount);
 
+        book.levels.insert(standardised_price, (price, amount));
         if standardised_amount > 0 {
             bids.push((standardised_price, standardised_amount));
         } else {
-            asks.push((standardised_price, standardised_amount.abs()));
+            asks.push((standardised_price, -standardised_amount));
         }
     }
 
@@ -203,4 +638,110 @@ fn map_initial_orderbook(pair: CurrencyPair, orders: Vec<(OrderId, Price, Amount
     };
 
     BroadcastType::One(broadcast)
+}
+
+// A level with a count of zero has been removed, with an amount of 1 for bids and -1 for asks
+// Otherwise the level holds the given amount, which also tells its side
+fn map_level_update(pair: CurrencyPair, book: &mut AggregatedBook, price: Price, count: Count, amount: Amount) -> BroadcastType {
+    let standardised_price = consumer::standardise_value(price);
+    let standardised_amount = consumer::standardise_value(amount);
+
+    let (mut bids, mut asks) = (vec!(), vec!());
+
+    let previous = if count == 0 {
+        book.levels.remove(&standardised_price)
+    } else {
+        book.levels.insert(standardised_price, (price, amount))
+    };
+
+    // A level that has crossed to the other side is removed from the side it was on
+    let removed_side = match previous {
+        Some((_, previous_amount)) if count == 0 || (previous_amount > 0.0) != (amount > 0.0) => Some(previous_amount > 0.0),
+        _ if count == 0 => Some(amount > 0.0),
+        _ => None
+    };
+    if let Some(is_bid) = removed_side {
+        if is_bid { bids.push((standardised_price, 0)) } else { asks.push((standardised_price, 0)) }
+    }
+
+    let mut broadcasts = vec!();
+
+    if !bids.is_empty() || !asks.is_empty() {
+        broadcasts.push(Broadcast::OrderbookRemove {
+            source: Exchange::Bitfinex,
+            pair: pair.clone(),
+            bids, asks
+        });
+    }
+
+    if count != 0 {
+        let level = (standardised_price, standardised_amount.abs());
+        let (bids, asks) = if standardised_amount > 0 { (vec!(level), vec!()) } else { (vec!(), vec!(level)) };
+        broadcasts.push(Broadcast::OrderbookUpdate {
+            source: Exchange::Bitfinex,
+            pair, bids, asks
+        });
+    }
+
+    BroadcastType::Many(broadcasts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries(json: &str) -> Vec<BookEntry> {
+        ::serde_json::from_str(json).expect("Book entries are valid JSON")
+    }
+
+    // Expected checksums are the signed CRC32 of the strings given, which is how the exchange calculates them
+    #[test]
+    fn checksums_top_aggregated_levels() {
+        // 30 levels each side, with a tiny amount that JavaScript writes in exponent form
+        let bids = (0..30).map(|i| format!("[{},1,{}]", 7000 - i, i + 1));
+        let asks = (0..30).map(|i| if i == 2 { "[7003,1,-1e-7]".to_string() } else { format!("[{},1,{}]", 7001 + i, -(i + 1)) });
+        let json = format!("[{}]", bids.chain(asks).collect::<Vec<String>>().join(","));
+
+        let mut book = Book::Aggregated(AggregatedBook::default());
+        map_initial_book(CurrencyPair::new("BTC", "USD"), &mut book, entries(&json));
+
+        // 7000:1:7001:-1:6999:2:7002:-2:6998:3:7003:-1e-7:6997:4:7004:-4 ... 6976:25:7025:-25
+        assert_eq!(book.checksum(), -1353321107);
+    }
+
+    #[test]
+    fn checksums_raw_orders_ranked_by_id() {
+        let json = "[[20,0.0001,1.5],[10,0.0001,2],[30,0.0002,-1],[5,0.00009,0.5]]";
+
+        let mut book = Book::Raw(RawBook::default());
+        map_initial_book(CurrencyPair::new("XRP", "BTC"), &mut book, entries(json));
+
+        // 10:2:30:-1:20:1.5:5:0.5
+        assert_eq!(book.checksum(), -567112837);
+    }
+    #[test]
+    fn tells_channel_messages_apart() {
+        let parse = |json: &str| ::serde_json::from_str::<Response>(json).expect("Channel messages are valid responses");
+
+        match parse(r#"[17,"hb",5]"#) {
+            Response::Heartbeat(17, HeartbeatTag::Heartbeat, 5) => (),
+            other => panic!("Expected a heartbeat, got {:?}", other)
+        }
+        match parse(r#"[17,"cs",-567112837,6]"#) {
+            Response::Checksum(17, ChecksumTag::Checksum, -567112837, 6) => (),
+            other => panic!("Expected a checksum, got {:?}", other)
+        }
+        match parse("[17,[7000,1,2.5],7]") {
+            Response::OrderbookUpdate(17, _, 7) => (),
+            other => panic!("Expected a book update, got {:?}", other)
+        }
+        match parse(r#"{"event":"unsubscribed","status":"OK","chanId":17}"#) {
+            Response::UnsubscribeConfirmation { channel_id: 17, .. } => (),
+            other => panic!("Expected an unsubscribe confirmation, got {:?}", other)
+        }
+        match parse(r#"{"event":"conf","status":"OK","flags":196608}"#) {
+            Response::ConfConfirmation { flags: 196608, .. } => (),
+            other => panic!("Expected a configuration confirmation, got {:?}", other)
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,745 @@
+use super::{Broadcast, Channel, Sequenced, SlowClientPolicy, Subscription, Timestamp};
+use super::encoding::{Encoded, Encoding};
+use super::outbox::{Command, Outbox};
+use super::protobuf::{self, ToProtobuf};
+use super::throttle::Throttled;
+
+use config::SlowClientConfig;
+use domain::{CurrencyPair, Exchange, MarketState};
+use std::collections::{HashMap, HashSet};
+use std::iter;
+use std::sync::{Arc, Mutex, atomic::{AtomicUsize, Ordering}};
+use ws;
+
+// Timeout for a disconnected slow client to finish closing, since it may never read the close frame
+pub const CLOSE_TIMEOUT: ws::util::Token = ws::util::Token(1);
+const CLOSE_TIMEOUT_MS: u64 = 5000;
+// Each throttled subscription has its own timeout, numbered from here
+const FIRST_THROTTLE_TOKEN: usize = 2;
+
+// Broadcasts on the same channel, exchange and pair form a stream with its own sequence numbers
+pub type StreamKey = (Channel, Option<Exchange>, Option<CurrencyPair>);
+
+struct Client {
+    sender: ClientSender,
+    encoding: Encoding,
+    subscriptions: Vec<Subscription>,
+    throttled: Vec<Throttled>,
+    next_token: usize,
+    // Set once the client falls too far behind, until it has caught up again
+    lagging: bool,
+    // Books whose updates were dropped while the client was lagging
+    stale: HashSet<StreamKey>
+}
+
+// What happens to a broadcast on its way to a client
+enum Backlog {
+    Deliver,
+    Drop,
+    Disconnect
+}
+
+impl Client {
+    fn subscribed_to(&self, subscription: &Subscription) -> bool {
+        self.subscriptions.iter().any(|existing| existing.same_topic(subscription))
+            || self.throttled.iter().any(|existing| existing.subscription.same_topic(subscription))
+    }
+
+    // Marks the client as lagging once it reaches the limit, returning true if it should be disconnected instead
+    // Clients twice the limit behind are disconnected whatever the policy, since they are not keeping up
+    // even with the updates that are not dropped
+    fn behind(&mut self, config: &SlowClientConfig) -> bool {
+        let queued = self.sender.queued();
+        if queued >= config.max_queued.saturating_mul(2)
+            || (queued >= config.max_queued && config.policy == SlowClientPolicy::Disconnect) {
+            return true;
+        }
+
+        if !self.lagging && queued >= config.max_queued {
+            warn!("Client {} is {} messages behind, dropping updates to it until it catches up",
+                  self.sender.connection_id(), queued);
+            self.lagging = true;
+            // Finds out as soon as possible when the client has received everything sent so far
+            self.sender.ping();
+        }
+        false
+    }
+
+    fn backlog(&mut self, broadcast: &Broadcast, config: &SlowClientConfig) -> Backlog {
+        if self.behind(config) {
+            return Backlog::Disconnect;
+        }
+
+        if !self.lagging {
+            return Backlog::Deliver;
+        }
+
+        match (config.policy, stream_key(broadcast)) {
+            (SlowClientPolicy::Resync, Some(_)) => Backlog::Drop,
+            (SlowClientPolicy::Conflate, Some(key)) => match key.0 {
+                Channel::Book | Channel::Orders | Channel::ConsolidatedBook | Channel::BestBidOffer => {
+                    self.stale.insert(key);
+                    Backlog::Drop
+                },
+                Channel::Trades | Channel::Connection => Backlog::Deliver
+            },
+            _ => Backlog::Deliver
+        }
+    }
+}
+
+// Broadcasts routed by topic are sent with their sequence number, and the rest are sent as they are
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Outgoing<'a> {
+    Sequenced(Sequenced<'a>),
+    Direct(&'a Broadcast)
+}
+
+impl<'a> ToProtobuf for Outgoing<'a> {
+    fn to_protobuf(&self) -> protobuf::schema::Broadcast {
+        match *self {
+            Outgoing::Sequenced(ref sequenced) => protobuf::broadcast(sequenced.broadcast, Some(sequenced.seq), Some(sequenced.ts)),
+            Outgoing::Direct(broadcast) => protobuf::broadcast(broadcast, None, None)
+        }
+    }
+}
+
+// Sends to a single client through the outbox, counting the messages it has not been seen to receive yet
+// Messages sit in the connection's write buffer for as long as the client does not read them, so every few
+// messages the count sent so far goes out in a ping, and the pong that echoes it confirms everything before it
+#[derive(Clone)]
+pub struct ClientSender {
+    sender: ws::Sender,
+    outbox: Outbox,
+    sent: Arc<AtomicUsize>,
+    acknowledged: Arc<AtomicUsize>,
+    ping_every: usize
+}
+
+impl ClientSender {
+    pub fn new(sender: ws::Sender, outbox: Outbox, ping_every: usize) -> Self {
+        Self {
+            sender,
+            outbox,
+            sent: Arc::new(AtomicUsize::new(0)),
+            acknowledged: Arc::new(AtomicUsize::new(0)),
+            ping_every: ping_every.max(1)
+        }
+    }
+
+    // ws errors are passed through as ws returns them
+    #[allow(clippy::result_large_err)]
+    pub fn send<M: Into<ws::Message>>(&self, msg: M) -> ws::Result<()> {
+        self.outbox.post(&self.sender, Command::Send(msg.into()))?;
+        if (self.sent.fetch_add(1, Ordering::SeqCst) + 1).is_multiple_of(self.ping_every) {
+            self.ping();
+        }
+        Ok(())
+    }
+
+    pub fn ping(&self) {
+        let sent = self.sent.load(Ordering::SeqCst) as u64;
+        let payload = (0..8).rev().map(|byte| (sent >> (byte * 8)) as u8).collect();
+        if let Err(e) = self.outbox.post(&self.sender, Command::Ping(payload)) {
+            warn!("Could not ping client {}: {}", self.connection_id(), e);
+        }
+    }
+
+    // Called with the payload of each pong, which is ignored unless it echoes one of our pings
+    pub fn acknowledge(&self, payload: &[u8]) {
+        if payload.len() != 8 {
+            return;
+        }
+        let count = payload.iter().fold(0u64, |count, &byte| (count << 8) | byte as u64) as usize;
+        if count > self.sent.load(Ordering::SeqCst) {
+            return;
+        }
+
+        let mut acknowledged = self.acknowledged.load(Ordering::SeqCst);
+        while count > acknowledged {
+            match self.acknowledged.compare_exchange(acknowledged, count, Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_) => return,
+                Err(current) => acknowledged = current
+            }
+        }
+    }
+
+    pub fn queued(&self) -> usize {
+        self.sent.load(Ordering::SeqCst).saturating_sub(self.acknowledged.load(Ordering::SeqCst))
+    }
+
+    pub fn timeout(&self, ms: u64, token: ws::util::Token) {
+        if let Err(e) = self.outbox.post(&self.sender, Command::Timeout(ms, token)) {
+            warn!("Could not schedule a timeout for client {}: {}", self.connection_id(), e);
+        }
+    }
+
+    // Closes the connection, and drops it if the client does not complete the closing handshake in time
+    #[allow(clippy::result_large_err)]
+    pub fn close(&self, reason: &'static str) {
+        let closed = self.outbox.post(&self.sender, Command::Close(ws::CloseCode::Policy, reason))
+            .and_then(|_| self.outbox.post(&self.sender, Command::Timeout(CLOSE_TIMEOUT_MS, CLOSE_TIMEOUT)));
+        if let Err(e) = closed {
+            warn!("Could not close the connection to client {}: {}", self.connection_id(), e);
+        }
+    }
+
+    pub fn connection_id(&self) -> u32 {
+        self.sender.connection_id()
+    }
+}
+
+struct Inner {
+    clients: HashMap<u32, Client>,
+    // Sequence number of the last broadcast dispatched on each stream
+    sequences: HashMap<StreamKey, u64>,
+    // Only changed while dispatching, so snapshots taken under the lock match the sequence numbers above
+    state: MarketState,
+    slow_clients: SlowClientConfig
+}
+
+// Every client connected to the server, keyed by connection ID
+#[derive(Clone)]
+pub struct Clients {
+    inner: Arc<Mutex<Inner>>
+}
+
+impl Clients {
+    pub fn new(slow_clients: SlowClientConfig, state: MarketState) -> Self {
+        let inner = Inner { clients: HashMap::new(), sequences: HashMap::new(), state, slow_clients };
+        Self { inner: Arc::new(Mutex::new(inner)) }
+    }
+
+    pub fn add(&self, sender: ClientSender, encoding: Encoding) {
+        let id = sender.connection_id();
+        let client = Client {
+            sender,
+            encoding,
+            subscriptions: vec!(),
+            throttled: vec!(),
+            next_token: FIRST_THROTTLE_TOKEN,
+            lagging: false,
+            stale: HashSet::new()
+        };
+        self.inner.lock().expect("Clients lock was poisoned").clients.insert(id, client);
+    }
+
+    pub fn remove(&self, id: u32) {
+        self.inner.lock().expect("Clients lock was poisoned").clients.remove(&id);
+    }
+
+    // Acknowledges the subscription and sends the snapshots for it, returning false if the client was already subscribed
+    // Updates are applied to the market state under the same lock, so the snapshots include every update
+    // numbered up to their sequence number and none after it
+    pub fn subscribe<F>(&self, id: u32, subscription: Subscription, snapshots: F) -> bool
+        where F: FnOnce(&Subscription) -> Vec<Broadcast> {
+        let mut inner = self.inner.lock().expect("Clients lock was poisoned");
+        let Inner { ref mut clients, ref sequences, .. } = *inner;
+
+        match clients.get_mut(&id) {
+            Some(ref mut client) if !client.subscribed_to(&subscription) => {
+                client.subscriptions.push(subscription.clone());
+
+                send(client, &Broadcast::Subscribed { subscription: subscription.clone() }, sequences);
+                for snapshot in snapshots(&subscription) {
+                    send(client, &snapshot, sequences);
+                }
+
+                true
+            },
+            _ => false
+        }
+    }
+
+    // Acknowledges a throttled subscription and sends the first update for it straight away, then one every interval
+    // Returns false if the client was already subscribed
+    pub fn subscribe_throttled(&self, id: u32, subscription: Subscription) -> bool {
+        let mut inner = self.inner.lock().expect("Clients lock was poisoned");
+        let Inner { ref mut clients, ref sequences, ref state, .. } = *inner;
+
+        let client = match clients.get_mut(&id) {
+            Some(client) => client,
+            None => return false
+        };
+        if client.subscribed_to(&subscription) {
+            return false;
+        }
+
+        let token = ws::util::Token(client.next_token);
+        let mut throttled = match Throttled::new(subscription.clone(), token) {
+            Some(throttled) => throttled,
+            None => return false
+        };
+        client.next_token += 1;
+
+        send(client, &Broadcast::Subscribed { subscription }, sequences);
+        for (update, seq) in throttled.flush(&state.books) {
+            send_numbered(client, &update, Some(seq));
+        }
+        client.sender.timeout(throttled.interval(), token);
+        client.throttled.push(throttled);
+
+        true
+    }
+
+    // Sends the changes gathered for a throttled subscription since its last update, and schedules the next one
+    // Nothing is sent while the client is lagging, so its changes build up until it catches up
+    pub fn flush(&self, id: u32, token: ws::util::Token) {
+        let mut inner = self.inner.lock().expect("Clients lock was poisoned");
+        let Inner { ref mut clients, ref sequences, ref state, slow_clients } = *inner;
+
+        let behind = match clients.get_mut(&id) {
+            Some(client) => client.behind(&slow_clients),
+            None => return
+        };
+        if behind {
+            if let Some(client) = clients.remove(&id) {
+                disconnect(client, &slow_clients, sequences);
+            }
+            return;
+        }
+
+        let client = clients.get_mut(&id).expect("Client was just found");
+        let lagging = client.lagging;
+        // Unsubscribing leaves the timeout running, and it stops here
+        let (updates, interval) = match client.throttled.iter_mut().find(|throttled| throttled.token == token) {
+            Some(throttled) => (if lagging { vec!() } else { throttled.flush(&state.books) }, throttled.interval()),
+            None => return
+        };
+
+        for (update, seq) in updates {
+            send_numbered(client, &update, Some(seq));
+        }
+        client.sender.timeout(interval, token);
+    }
+
+    // Returns false if the client was not subscribed
+    pub fn unsubscribe(&self, id: u32, subscription: &Subscription) -> bool {
+        let mut inner = self.inner.lock().expect("Clients lock was poisoned");
+        match inner.clients.get_mut(&id) {
+            Some(ref mut client) => {
+                let before = client.subscriptions.len() + client.throttled.len();
+                client.subscriptions.retain(|existing| !existing.same_topic(subscription));
+                client.throttled.retain(|existing| !existing.subscription.same_topic(subscription));
+                client.subscriptions.len() + client.throttled.len() != before
+            },
+            None => false
+        }
+    }
+
+    // Sends every book covered by a throttled subscription afresh, as snapshots whatever the mode
+    // Returns false if the client has no throttled subscription for the topic
+    pub fn resend_throttled(&self, id: u32, subscription: &Subscription) -> bool {
+        let mut inner = self.inner.lock().expect("Clients lock was poisoned");
+        let Inner { ref mut clients, ref state, .. } = *inner;
+
+        let client = match clients.get_mut(&id) {
+            Some(client) => client,
+            None => return false
+        };
+        let updates = match client.throttled.iter_mut().find(|throttled| throttled.subscription.same_topic(subscription)) {
+            Some(throttled) => {
+                throttled.reset();
+                throttled.flush(&state.books)
+            },
+            None => return false
+        };
+
+        for (update, seq) in updates {
+            send_numbered(client, &update, Some(seq));
+        }
+        true
+    }
+
+    // Send broadcasts to a single client, tagged with the current sequence number of their streams
+    // Used for snapshots, which must be ordered against dispatched updates in the same way as when subscribing
+    pub fn respond<F: FnOnce() -> Vec<Broadcast>>(&self, id: u32, responses: F) {
+        let inner = self.inner.lock().expect("Clients lock was poisoned");
+        if let Some(client) = inner.clients.get(&id) {
+            for response in responses() {
+                send(client, &response, &inner.sequences);
+            }
+        }
+    }
+
+    // Called whenever the client confirms receiving messages, to resume updates once a lagging client has caught up
+    // The client is told what was dropped, then sent snapshots replacing it under the same lock as dispatching
+    // Throttled subscriptions start over from snapshots in their own sequence, since what was forwarded for them
+    // may have been dropped too
+    pub fn caught_up<F>(&self, id: u32, snapshots: F)
+        where F: Fn(&Subscription) -> Vec<Broadcast> {
+        let mut inner = self.inner.lock().expect("Clients lock was poisoned");
+        let Inner { ref mut clients, ref sequences, ref state, slow_clients } = *inner;
+
+        let client = match clients.get_mut(&id) {
+            Some(client) => client,
+            None => return
+        };
+        if !client.lagging || client.sender.queued() > slow_clients.max_queued / 2 {
+            return;
+        }
+
+        info!("Client {} has caught up, resuming updates", id);
+        client.lagging = false;
+
+        let resync: Vec<Subscription> = match slow_clients.policy {
+            SlowClientPolicy::Conflate => {
+                let subscriptions = &client.subscriptions;
+                client.stale.drain()
+                    .map(|(channel, exchange, pair)| Subscription { channel, exchange, pair, throttle: None })
+                    .filter(|stale| subscriptions.iter().any(|subscription| subscription.channel == stale.channel
+                        && subscription.covers(stale.exchange, stale.pair.as_ref())))
+                    .collect()
+            },
+            _ => client.subscriptions.clone()
+        };
+        let throttled_resync: Vec<(Broadcast, u64)> = client.throttled.iter_mut()
+            .flat_map(|throttled| {
+                throttled.reset();
+                throttled.flush(&state.books)
+            })
+            .collect();
+
+        let message = match slow_clients.policy {
+            SlowClientPolicy::Conflate => "Book updates were dropped while you were too far behind, \
+                the snapshots that follow replace them",
+            _ => "Updates were dropped while you were too far behind, the snapshots that follow replace them"
+        };
+        send(client, &Broadcast::SlowClient {
+            policy: slow_clients.policy,
+            limit: slow_clients.max_queued,
+            message: message.to_string()
+        }, sequences);
+
+        for subscription in resync {
+            for snapshot in snapshots(&subscription) {
+                send(client, &snapshot, sequences);
+            }
+        }
+        for (snapshot, seq) in throttled_resync {
+            send_numbered(client, &snapshot, Some(seq));
+        }
+    }
+
+    // Everything sent to the client after the acknowledgement, including the acknowledgement itself, uses the new encoding
+    pub fn set_encoding(&self, id: u32, encoding: Encoding) {
+        let mut inner = self.inner.lock().expect("Clients lock was poisoned");
+        let Inner { ref mut clients, ref sequences, .. } = *inner;
+
+        if let Some(client) = clients.get_mut(&id) {
+            client.encoding = encoding;
+            send(client, &Broadcast::Encoding { encoding }, sequences);
+        }
+    }
+
+    // Number of messages each client has not confirmed receiving, ordered by connection ID
+    // Confirmations only come with each pong, so a client that is keeping up can still show up to ping_every messages
+    pub fn queue_depths(&self) -> Vec<(u32, usize)> {
+        let inner = self.inner.lock().expect("Clients lock was poisoned");
+        let mut depths: Vec<(u32, usize)> = inner.clients.iter().map(|(&id, client)| (id, client.sender.queued())).collect();
+        depths.sort_unstable();
+        depths
+    }
+
+    // Send a broadcast to every client, whatever it is subscribed to
+    pub fn send_all(&self, broadcast: &Broadcast) {
+        let inner = self.inner.lock().expect("Clients lock was poisoned");
+        let outgoing = outgoing(broadcast, current(broadcast, &inner.sequences));
+        let mut encoded = Encoded::new(&outgoing);
+
+        for client in inner.clients.values() {
+            deliver(client, &mut encoded);
+        }
+    }
+
+    // Apply a broadcast to the market state, then send it and any broadcasts resulting from the change
+    // to every client subscribed to them
+    pub fn dispatch(&self, broadcast: Broadcast, ts: Timestamp) {
+        let mut inner = self.inner.lock().expect("Clients lock was poisoned");
+        let Inner { ref mut clients, ref mut sequences, ref state, slow_clients } = *inner;
+
+        for (broadcast, seq) in apply(state, sequences, broadcast, ts) {
+            route(clients, sequences, &slow_clients, &broadcast, seq);
+        }
+    }
+}
+
+// Send a broadcast to every client subscribed to it, serializing it at most once for each encoding
+// Broadcasts without a topic go to every client
+// Clients that have fallen too far behind are handled according to the slow client policy
+fn route(clients: &mut HashMap<u32, Client>, sequences: &HashMap<StreamKey, u64>, slow_clients: &SlowClientConfig,
+         broadcast: &Broadcast, seq: Option<u64>) {
+    let key = stream_key(broadcast);
+    let mut recipients = vec!();
+    let mut forwarded = vec!();
+    let mut disconnected = vec!();
+    for (&id, client) in clients.iter_mut() {
+        // Throttled subscriptions take note of the change for their next update instead, and any they pass on
+        // are numbered in their own sequence unless the client is also subscribed to the stream itself
+        let mut forward = vec!();
+        if let Some(ref key) = key {
+            forward = client.throttled.iter_mut()
+                .filter_map(|throttled| throttled.note(key, broadcast))
+                .collect();
+            if client.subscriptions.iter().any(|subscription| subscription.matches(broadcast)) {
+                forward.clear();
+            } else if forward.is_empty() {
+                continue;
+            }
+        }
+        match client.backlog(broadcast, slow_clients) {
+            Backlog::Deliver if forward.is_empty() => recipients.push(id),
+            Backlog::Deliver => forwarded.push((id, forward)),
+            Backlog::Drop => (),
+            Backlog::Disconnect => disconnected.push(id)
+        }
+    }
+
+    for (id, seqs) in forwarded {
+        for seq in seqs {
+            send_numbered(&clients[&id], broadcast, Some(seq));
+        }
+    }
+
+    // Disconnected clients are removed straight away, so nothing more builds up for them while they close
+    for id in disconnected {
+        if let Some(client) = clients.remove(&id) {
+            disconnect(client, slow_clients, sequences);
+        }
+    }
+
+    if recipients.is_empty() {
+        return;
+    }
+
+    let outgoing = outgoing(broadcast, seq);
+    let mut encoded = Encoded::new(&outgoing);
+
+    for id in recipients {
+        deliver(&clients[&id], &mut encoded);
+    }
+}
+
+// Applies a broadcast to the market state, returning it and any broadcasts resulting from the change with their
+// sequence numbers, which are taken as the state changes so that every change moves its stream on by exactly one
+fn apply(state: &MarketState, sequences: &mut HashMap<StreamKey, u64>, broadcast: Broadcast, ts: Timestamp)
+    -> Vec<(Broadcast, Option<u64>)> {
+    let derived = state.record(&broadcast, ts);
+    iter::once(broadcast).chain(derived)
+        .map(|broadcast| {
+            let seq = stream_key(&broadcast).map(|key| {
+                let seq = sequences.entry(key).or_insert(0);
+                *seq += 1;
+                *seq
+            });
+            (broadcast, seq)
+        })
+        .collect()
+}
+
+fn disconnect(client: Client, slow_clients: &SlowClientConfig, sequences: &HashMap<StreamKey, u64>) {
+    let queued = client.sender.queued();
+    warn!("Client {} is {} messages behind, disconnecting it", client.sender.connection_id(), queued);
+
+    let message = format!("Disconnected for falling {} messages behind, the limit is {}", queued, slow_clients.max_queued);
+    send(&client, &Broadcast::SlowClient {
+        policy: SlowClientPolicy::Disconnect,
+        limit: slow_clients.max_queued,
+        message
+    }, sequences);
+    client.sender.close("Too far behind receiving messages");
+}
+
+fn stream_key(broadcast: &Broadcast) -> Option<StreamKey> {
+    broadcast.topic().map(|(channel, exchange, pair)| (channel, exchange, pair.cloned()))
+}
+
+// Sequence number of the last broadcast dispatched on the stream, for broadcasts that are not themselves dispatched
+fn current(broadcast: &Broadcast, sequences: &HashMap<StreamKey, u64>) -> Option<u64> {
+    stream_key(broadcast).map(|key| sequences.get(&key).cloned().unwrap_or(0))
+}
+
+fn outgoing<'a>(broadcast: &'a Broadcast, seq: Option<u64>) -> Outgoing<'a> {
+    match seq {
+        Some(seq) => Outgoing::Sequenced(Sequenced { broadcast, seq, ts: ::consumer::timestamp() }),
+        None => Outgoing::Direct(broadcast)
+    }
+}
+
+fn send(client: &Client, broadcast: &Broadcast, sequences: &HashMap<StreamKey, u64>) {
+    send_numbered(client, broadcast, current(broadcast, sequences));
+}
+
+fn send_numbered(client: &Client, broadcast: &Broadcast, seq: Option<u64>) {
+    deliver(client, &mut Encoded::new(&outgoing(broadcast, seq)));
+}
+
+fn deliver(client: &Client, encoded: &mut Encoded<Outgoing>) {
+    match encoded.get(client.encoding) {
+        Ok(msg) => client.sender.send(msg)
+            .unwrap_or_else(|e| error!("Could not send to client {}: {}", client.sender.connection_id(), e)),
+        Err(e) => error!("Could not serialize broadcast as {:?}: {}", client.encoding, e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use broadcast_api::{Price, Throttle, ThrottleMode, Volume};
+    use domain::OrderBook;
+    use std::sync::mpsc;
+
+    type Levels = Vec<(Price, Volume)>;
+
+    fn pair() -> CurrencyPair {
+        CurrencyPair::new("XRP", "BTC")
+    }
+
+    // Updates and removals cycling over a handful of price levels on both sides
+    fn updates() -> Vec<Broadcast> {
+        (0..60).map(|i: i64| {
+            let bids = vec!((100 - i % 7, i + 1));
+            let asks = vec!((200 + i % 5, i + 1));
+            if i % 3 == 0 {
+                Broadcast::OrderbookRemove { source: Exchange::Bitfinex, pair: pair(), bids, asks }
+            } else {
+                Broadcast::OrderbookUpdate { source: Exchange::Bitfinex, pair: pair(), bids, asks }
+            }
+        }).collect()
+    }
+
+    fn apply_to(book: &mut OrderBook, broadcast: &Broadcast) {
+        match *broadcast {
+            Broadcast::OrderbookSnapshot { ref bids, ref asks, .. } => book.replace(bids, asks),
+            Broadcast::OrderbookUpdate { ref bids, ref asks, .. } => book.update(bids, asks),
+            Broadcast::OrderbookRemove { ref bids, ref asks, .. } => book.remove(bids, asks),
+            _ => panic!("Not a book broadcast: {:?}", broadcast)
+        }
+    }
+
+    #[test]
+    fn snapshots_and_updates_interleave() {
+        let state = MarketState::new();
+        let mut sequences = HashMap::new();
+        let key: StreamKey = (Channel::Book, Some(Exchange::Bitfinex), Some(pair()));
+
+        let initial = Broadcast::OrderbookSnapshot {
+            source: Exchange::Bitfinex,
+            pair: pair(),
+            bids: vec!((100, 5), (99, 5)),
+            asks: vec!((200, 5), (201, 5))
+        };
+
+        // A client may subscribe between any two dispatches, and gets the book as it stands with the current seq
+        let mut dispatched: Vec<(Broadcast, u64)> = vec!();
+        let mut snapshots: Vec<(u64, Levels, Levels)> = vec!();
+        for broadcast in iter::once(initial).chain(updates()) {
+            for (broadcast, seq) in apply(&state, &mut sequences, broadcast, 0) {
+                if stream_key(&broadcast) == Some(key.clone()) {
+                    dispatched.push((broadcast, seq.expect("Book broadcasts are sequenced")));
+                }
+            }
+            let (bids, asks) = state.books.book(Exchange::Bitfinex, &pair()).expect("Book was recorded").top(usize::MAX);
+            snapshots.push((sequences[&key], bids, asks));
+        }
+
+        let seqs: Vec<u64> = dispatched.iter().map(|&(_, seq)| seq).collect();
+        assert_eq!(seqs, (1..dispatched.len() as u64 + 1).collect::<Vec<u64>>());
+
+        let expected = state.books.book(Exchange::Bitfinex, &pair()).expect("Book was recorded").top(usize::MAX);
+        for (seq, bids, asks) in snapshots {
+            let mut book = OrderBook::default();
+            book.replace(&bids, &asks);
+            for (broadcast, _) in dispatched.iter().filter(|&&(_, update_seq)| update_seq > seq) {
+                apply_to(&mut book, broadcast);
+            }
+            assert_eq!(book.top(usize::MAX), expected, "Snapshot at seq {} did not line up with the updates after it", seq);
+        }
+    }
+    fn snapshot(source: Exchange, state: &MarketState) -> Broadcast {
+        let (bids, asks) = state.books.book(source, &pair()).expect("Book was recorded").top(usize::MAX);
+        Broadcast::OrderbookSnapshot { source, pair: pair(), bids, asks }
+    }
+
+    fn book_subscription(exchange: Exchange, throttle: Option<Throttle>) -> Subscription {
+        Subscription { channel: Channel::Book, exchange: Some(exchange), pair: Some(pair()), throttle }
+    }
+
+    // The type, exchange and sequence number of a message
+    type Posted = (String, Option<String>, Option<u64>);
+
+    // Stands in for the connection, which is never opened
+    struct Unconnected;
+
+    impl ws::Handler for Unconnected {}
+
+    // Each message posted for the client, along with the last ping
+    fn posted(commands: &mpsc::Receiver<(ws::Sender, Command)>) -> (Vec<Posted>, Option<Vec<u8>>) {
+        let (mut messages, mut ping) = (vec!(), None);
+        for (_, command) in commands.try_iter() {
+            match command {
+                Command::Send(ws::Message::Text(text)) => {
+                    let json: ::serde_json::Value = ::serde_json::from_str(&text).expect("Messages are JSON");
+                    let (tag, body) = json.as_object().and_then(|object| object.iter().next()).expect("Messages are tagged");
+                    let exchange = body.get("source").and_then(|source| source.as_str()).map(str::to_string);
+                    messages.push((tag.clone(), exchange, json.get("seq").and_then(|seq| seq.as_u64())));
+                },
+                Command::Ping(payload) => ping = Some(payload),
+                _ => ()
+            }
+        }
+        (messages, ping)
+    }
+
+    #[test]
+    fn resyncs_lagging_clients_once_caught_up() {
+        let state = MarketState::new();
+        let clients = Clients::new(SlowClientConfig { max_queued: 4, policy: SlowClientPolicy::Resync }, state.clone());
+        let (outbox, commands) = Outbox::detached();
+        let socket = ws::WebSocket::new(|_| Unconnected).expect("WebSocket can be built");
+        let sender = ClientSender::new(socket.broadcaster(), outbox, 100);
+        let id = sender.connection_id();
+        clients.add(sender.clone(), Encoding::Json);
+
+        let update = |source, price| Broadcast::OrderbookUpdate { source, pair: pair(), bids: vec!((price, 1)), asks: vec!() };
+        clients.dispatch(Broadcast::OrderbookSnapshot { source: Exchange::Bitfinex, pair: pair(), bids: vec!((100, 5)), asks: vec!() }, 0);
+        clients.dispatch(Broadcast::OrderbookSnapshot { source: Exchange::Poloniex, pair: pair(), bids: vec!((99, 5)), asks: vec!() }, 0);
+
+        let throttle = Throttle { interval: 1000, depth: None, mode: ThrottleMode::Delta };
+        assert!(clients.subscribe(id, book_subscription(Exchange::Bitfinex, None), |_| vec!()));
+        assert!(clients.subscribe_throttled(id, book_subscription(Exchange::Poloniex, Some(throttle))));
+        clients.dispatch(update(Exchange::Bitfinex, 98), 0);
+
+        // The client is now as far behind as it may be, so what follows is dropped, including the invalidation
+        // the throttled subscription would pass straight on
+        clients.dispatch(update(Exchange::Bitfinex, 97), 0);
+        clients.dispatch(Broadcast::OrderbookInvalidated { source: Exchange::Poloniex, pair: pair(), ts: 0 }, 0);
+        clients.dispatch(Broadcast::OrderbookSnapshot { source: Exchange::Poloniex, pair: pair(), bids: vec!((96, 2)), asks: vec!() }, 0);
+        clients.dispatch(update(Exchange::Bitfinex, 95), 0);
+
+        let bitfinex = Some("bitfinex".to_string());
+        let poloniex = Some("poloniex".to_string());
+        let (messages, ping) = posted(&commands);
+        assert_eq!(messages, vec!(
+            ("subscribed".to_string(), None, None),
+            ("subscribed".to_string(), None, None),
+            ("orderbookSnapshot".to_string(), poloniex.clone(), Some(1)),
+            ("orderbookUpdate".to_string(), bitfinex.clone(), Some(2))));
+
+        // Catching up is only noticed once a pong confirms everything sent
+        clients.caught_up(id, |subscription| vec!(snapshot(subscription.exchange.unwrap(), &state)));
+        assert!(posted(&commands).0.is_empty());
+
+        sender.acknowledge(&ping.expect("Lagging clients are pinged"));
+        clients.caught_up(id, |subscription| vec!(snapshot(subscription.exchange.unwrap(), &state)));
+        let (messages, _) = posted(&commands);
+        assert_eq!(messages, vec!(
+            ("slowClient".to_string(), None, None),
+            ("orderbookSnapshot".to_string(), bitfinex.clone(), Some(4)),
+            ("orderbookSnapshot".to_string(), poloniex.clone(), Some(3))));
+
+        clients.dispatch(update(Exchange::Bitfinex, 94), 0);
+        assert_eq!(posted(&commands).0, vec!(("orderbookUpdate".to_string(), bitfinex, Some(5))));
+    }
+}
\ No newline at end of file
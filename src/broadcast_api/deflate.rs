@@ -0,0 +1,304 @@
+use config::CompressionConfig;
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress};
+use std::io;
+use std::mem;
+use ws;
+
+const EXTENSION: &str = "permessage-deflate";
+// Every compressed message ends with this, and it is left off on the wire
+const TAIL: &[u8] = &[0x00, 0x00, 0xff, 0xff];
+// Requests from clients are small, so any message that inflates beyond this is refused, however many frames it is split into
+const MAX_INFLATED_LEN: usize = 1 << 20;
+
+// The permessage-deflate extension for a single client, as described in RFC 7692
+// Messages are always compressed with the largest window, so offers that limit the server window to less are declined
+pub struct Deflate {
+    compress: Compress,
+    decompress: Decompress,
+    // Whether each message is compressed or decompressed without reference to the ones before it
+    reset_compress: bool,
+    reset_decompress: bool,
+    // Only the first frame of a message says whether it is compressed
+    inflating: bool,
+    // Bytes inflated from the earlier frames of the message in progress
+    inflated_len: usize
+}
+
+impl Deflate {
+    // Accepts the first offer that can be agreed to, along with the extension to respond with
+    pub fn negotiate(config: &CompressionConfig, offers: &[&str]) -> Option<(Self, String)> {
+        offers.iter().filter_map(|offer| Self::accept(config, offer)).next()
+    }
+
+    fn accept(config: &CompressionConfig, offer: &str) -> Option<(Self, String)> {
+        let mut params = offer.split(';').map(|param| param.trim());
+        if params.next() != Some(EXTENSION) {
+            return None;
+        }
+
+        let mut reset_compress = !config.context_takeover;
+        let mut reset_decompress = !config.context_takeover;
+        let mut server_window = false;
+        let mut named = vec!();
+
+        for param in params {
+            let mut param = param.splitn(2, '=');
+            let name = param.next().unwrap_or("").trim();
+            let value = param.next().map(|value| value.trim().trim_matches('"'));
+
+            // An offer naming a parameter more than once is invalid
+            if named.contains(&name) {
+                return None;
+            }
+            named.push(name);
+
+            match (name, value) {
+                ("server_no_context_takeover", None) => reset_compress = true,
+                ("client_no_context_takeover", None) => reset_decompress = true,
+                // Messages compressed with any window can be decompressed with the largest one
+                ("client_max_window_bits", None) => (),
+                ("client_max_window_bits", Some(bits)) if window_bits(bits).is_some() => (),
+                ("server_max_window_bits", Some(bits)) if window_bits(bits) == Some(15) => server_window = true,
+                _ => return None
+            }
+        }
+
+        let mut extension = EXTENSION.to_string();
+        if reset_compress {
+            extension.push_str("; server_no_context_takeover");
+        }
+        if reset_decompress {
+            extension.push_str("; client_no_context_takeover");
+        }
+        // The response has to include a server window size that was offered (RFC 7692 7.1.2.1)
+        if server_window {
+            extension.push_str("; server_max_window_bits=15");
+        }
+
+        let deflate = Deflate {
+            compress: Compress::new(Compression::new(config.level), false),
+            decompress: Decompress::new(false),
+            reset_compress,
+            reset_decompress,
+            inflating: false,
+            inflated_len: 0
+        };
+
+        Some((deflate, extension))
+    }
+
+    // Compresses a whole message, which is what the server always sends
+    pub fn compress(&mut self, payload: &[u8]) -> io::Result<Vec<u8>> {
+        let mut compressed = Vec::with_capacity(payload.len() / 2 + TAIL.len() + 1);
+        let mut consumed = 0;
+
+        // Output is only complete once the flush leaves room to spare
+        loop {
+            if compressed.len() == compressed.capacity() {
+                compressed.reserve(payload.len() / 4 + 64);
+            }
+
+            let before = self.compress.total_in();
+            self.compress.compress_vec(&payload[consumed..], &mut compressed, FlushCompress::Sync)
+                .map_err(io::Error::other)?;
+            consumed += (self.compress.total_in() - before) as usize;
+
+            if consumed == payload.len() && compressed.len() < compressed.capacity() {
+                break;
+            }
+        }
+
+        if compressed.ends_with(TAIL) {
+            let len = compressed.len() - TAIL.len();
+            compressed.truncate(len);
+        }
+        if self.reset_compress {
+            self.compress.reset();
+        }
+
+        Ok(compressed)
+    }
+
+    // Inflates frames of compressed messages in place, which may be split across several frames
+    pub fn decompress(&mut self, frame: &mut ws::Frame) -> io::Result<()> {
+        match frame.opcode() {
+            ws::OpCode::Text | ws::OpCode::Binary => {
+                self.inflating = frame.has_rsv1();
+                self.inflated_len = 0;
+            },
+            ws::OpCode::Continue => (),
+            _ => return Ok(())
+        }
+        if !self.inflating {
+            return Ok(());
+        }
+
+        let mut payload = mem::take(frame.payload_mut());
+        if frame.is_final() {
+            payload.extend_from_slice(TAIL);
+        }
+
+        let mut inflated = Vec::with_capacity(payload.len() * 4);
+        let mut consumed = 0;
+
+        loop {
+            if inflated.len() == inflated.capacity() {
+                if self.inflated_len + inflated.len() >= MAX_INFLATED_LEN {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "message inflates beyond the largest allowed"));
+                }
+                inflated.reserve(payload.len() * 4);
+            }
+
+            let (before_in, before_out) = (self.decompress.total_in(), inflated.len());
+            self.decompress.decompress_vec(&payload[consumed..], &mut inflated, FlushDecompress::Sync)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            consumed += (self.decompress.total_in() - before_in) as usize;
+
+            if consumed == payload.len() && inflated.len() < inflated.capacity() {
+                break;
+            }
+            if self.decompress.total_in() == before_in && inflated.len() == before_out {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "compressed message is incomplete"));
+            }
+        }
+
+        self.inflated_len += inflated.len();
+        *frame.payload_mut() = inflated;
+        frame.set_rsv1(false);
+
+        if frame.is_final() {
+            self.inflating = false;
+            if self.reset_decompress {
+                self.decompress.reset(false);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Window sizes that may be negotiated, which are 8 to 15 bits
+fn window_bits(value: &str) -> Option<u8> {
+    value.parse().ok().filter(|bits| (8..=15).contains(bits))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(context_takeover: bool) -> CompressionConfig {
+        CompressionConfig { level: 6, context_takeover }
+    }
+
+    fn negotiate(context_takeover: bool, offer: &str) -> (Deflate, String) {
+        Deflate::negotiate(&config(context_takeover), &[offer]).expect("Offer was declined")
+    }
+
+    // Frames as a client would send them, with only the first marked as compressed
+    fn frames(compressed: &[u8], sizes: &[usize]) -> Vec<ws::Frame> {
+        let mut frames = vec!();
+        let mut rest = compressed;
+        for (i, &size) in sizes.iter().enumerate() {
+            let (chunk, remainder) = rest.split_at(size.min(rest.len()));
+            rest = remainder;
+            let last = i == sizes.len() - 1;
+            let opcode = if i == 0 { ws::OpCode::Text } else { ws::OpCode::Continue };
+            let mut frame = ws::Frame::message(chunk.to_vec(), opcode, last);
+            frame.set_rsv1(i == 0);
+            frames.push(frame);
+        }
+        frames
+    }
+
+    fn inflate(deflate: &mut Deflate, frames: Vec<ws::Frame>) -> io::Result<Vec<u8>> {
+        let mut message = vec!();
+        for mut frame in frames {
+            deflate.decompress(&mut frame)?;
+            message.extend(frame.into_data());
+        }
+        Ok(message)
+    }
+
+    fn round_trip(context_takeover: bool) {
+        let offer = "permessage-deflate; client_max_window_bits";
+        let (mut sender, _) = negotiate(context_takeover, offer);
+        let (mut receiver, _) = negotiate(context_takeover, offer);
+        let message = br#"{"orderbookUpdate":{"exchange":"bitfinex","pair":"XRP/BTC","bids":[[10000,500000000]],"asks":[]}}"#;
+
+        let first = sender.compress(message).unwrap();
+        let second = sender.compress(message).unwrap();
+        assert!(!first.ends_with(TAIL));
+
+        assert_eq!(inflate(&mut receiver, frames(&first, &[first.len()])).unwrap(), message.to_vec());
+        assert_eq!(inflate(&mut receiver, frames(&second, &[second.len()])).unwrap(), message.to_vec());
+
+        // A repeated message refers back to the first, unless each message is compressed on its own
+        if context_takeover {
+            assert!(second.len() < first.len());
+        } else {
+            assert_eq!(second, first);
+            let (mut fresh, _) = negotiate(context_takeover, offer);
+            assert_eq!(inflate(&mut fresh, frames(&second, &[second.len()])).unwrap(), message.to_vec());
+        }
+    }
+
+    #[test]
+    fn round_trips_with_context_takeover() {
+        round_trip(true);
+    }
+
+    #[test]
+    fn round_trips_without_context_takeover() {
+        round_trip(false);
+    }
+
+    #[test]
+    fn inflates_messages_split_across_frames() {
+        let (mut sender, _) = negotiate(true, "permessage-deflate");
+        let (mut receiver, _) = negotiate(true, "permessage-deflate");
+        let message: Vec<u8> = (0..2000).map(|i| format!("{{\"subscribe\":{}}}", i)).collect::<String>().into_bytes();
+
+        let compressed = sender.compress(&message).unwrap();
+        let third = compressed.len() / 3;
+        let inflated = inflate(&mut receiver, frames(&compressed, &[third, third, compressed.len()])).unwrap();
+
+        assert_eq!(inflated, message);
+    }
+
+    #[test]
+    fn refuses_messages_that_inflate_too_far() {
+        let (mut sender, _) = negotiate(true, "permessage-deflate");
+        let (mut receiver, _) = negotiate(true, "permessage-deflate");
+        let compressed = sender.compress(&vec!(b' '; MAX_INFLATED_LEN * 2)).unwrap();
+
+        let error = inflate(&mut receiver, frames(&compressed, &[compressed.len()])).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn refuses_split_messages_that_inflate_too_far() {
+        let (mut sender, _) = negotiate(true, "permessage-deflate");
+        let (mut receiver, _) = negotiate(true, "permessage-deflate");
+        let compressed = sender.compress(&vec!(b' '; MAX_INFLATED_LEN * 2)).unwrap();
+
+        // Each frame inflates to well under the limit, but the message as a whole does not
+        let frames = frames(&compressed, &[compressed.len() / 8 + 1; 8]);
+        let mut first = frames[0].clone();
+        negotiate(true, "permessage-deflate").0.decompress(&mut first).unwrap();
+        assert!(first.payload().len() < MAX_INFLATED_LEN / 2);
+
+        let error = inflate(&mut receiver, frames).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn echoes_offered_server_window() {
+        let (_, extension) = negotiate(true, "permessage-deflate; server_max_window_bits=15");
+        assert_eq!(extension, "permessage-deflate; server_max_window_bits=15");
+
+        let (_, extension) = negotiate(false, "permessage-deflate; client_max_window_bits");
+        assert_eq!(extension, "permessage-deflate; server_no_context_takeover; client_no_context_takeover");
+
+        assert!(Deflate::negotiate(&config(true), &["permessage-deflate; server_max_window_bits=10"]).is_none());
+    }
+}
\ No newline at end of file
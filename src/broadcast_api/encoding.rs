@@ -0,0 +1,74 @@
+use super::protobuf::{self, ToProtobuf};
+use serde::{Serialize, de::DeserializeOwned};
+use std::collections::HashMap;
+use ws;
+
+// Wire format of the messages sent to a client, negotiated as a WebSocket subprotocol or changed with a request
+// JSON is sent as text frames and the binary formats as binary frames
+// The protobuf schema is in proto/aggregator/broadcast/v1/broadcast.proto
+#[derive(Debug, Default, Serialize, Deserialize, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    #[default]
+    #[serde(rename = "json")]
+    Json,
+    #[serde(rename = "msgpack")]
+    MessagePack,
+    #[serde(rename = "cbor")]
+    Cbor,
+    #[serde(rename = "protobuf")]
+    Protobuf
+}
+
+impl Encoding {
+    // Subprotocols are named the same as the encodings are in requests
+    pub fn from_protocol(protocol: &str) -> Option<Encoding> {
+        match protocol {
+            "json" => Some(Encoding::Json),
+            "msgpack" => Some(Encoding::MessagePack),
+            "cbor" => Some(Encoding::Cbor),
+            "protobuf" => Some(Encoding::Protobuf),
+            _ => None
+        }
+    }
+
+    // MessagePack structs are written as maps, so that every encoding has the same field names as JSON
+    pub fn encode<T: Serialize + ToProtobuf>(self, value: &T) -> Result<ws::Message, String> {
+        match self {
+            Encoding::Json => ::serde_json::to_string(value).map(ws::Message::text).map_err(|e| e.to_string()),
+            Encoding::MessagePack => ::rmp_serde::to_vec_named(value).map(ws::Message::binary).map_err(|e| e.to_string()),
+            Encoding::Cbor => ::serde_cbor::to_vec(value).map(ws::Message::binary).map_err(|e| e.to_string()),
+            Encoding::Protobuf => Ok(ws::Message::binary(protobuf::encode(value)))
+        }
+    }
+
+    // Text frames are always JSON, whatever the encoding, and there is no protobuf schema for requests
+    pub fn decode<T: DeserializeOwned>(self, msg: &ws::Message) -> Result<T, String> {
+        match (msg, self) {
+            (ws::Message::Text(txt), _) => ::serde_json::from_str(txt).map_err(|e| e.to_string()),
+            (ws::Message::Binary(data), Encoding::Json) => ::serde_json::from_slice(data).map_err(|e| e.to_string()),
+            (ws::Message::Binary(data), Encoding::MessagePack) => ::rmp_serde::from_slice(data).map_err(|e| e.to_string()),
+            (ws::Message::Binary(data), Encoding::Cbor) => ::serde_cbor::from_slice(data).map_err(|e| e.to_string()),
+            (&ws::Message::Binary(_), Encoding::Protobuf) => Err("requests must be sent as JSON text".to_string())
+        }
+    }
+}
+
+// Encodes a message at most once for each encoding, however many clients it is sent to
+pub struct Encoded<'a, T: 'a> {
+    value: &'a T,
+    messages: HashMap<Encoding, ws::Message>
+}
+
+impl<'a, T: Serialize + ToProtobuf> Encoded<'a, T> {
+    pub fn new(value: &'a T) -> Self {
+        Self { value, messages: HashMap::new() }
+    }
+
+    pub fn get(&mut self, encoding: Encoding) -> Result<ws::Message, String> {
+        if !self.messages.contains_key(&encoding) {
+            let message = encoding.encode(self.value)?;
+            self.messages.insert(encoding, message);
+        }
+        Ok(self.messages[&encoding].clone())
+    }
+}
\ No newline at end of file
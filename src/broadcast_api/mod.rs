@@ -1,13 +1,36 @@
 pub mod server;
+pub mod clients;
+pub mod deflate;
+pub mod encoding;
+pub mod outbox;
+pub mod protobuf;
+pub mod throttle;
 
 use super::domain::*;
+use self::encoding::Encoding;
+use std::fmt;
+use std::str::FromStr;
 
 pub type Timestamp = i64;
 pub type Price = i64;
 pub type Volume = i64;
 pub type Total = i64;
+pub type TradeDetails = (Timestamp, Price, Volume, Total);
+pub type OrderId = i64;
 
-#[derive(Debug, Serialize)]
+// An individual order: ID, price and volume
+pub type Order = (OrderId, Price, Volume);
+
+// A price level across every exchange: price, total volume and the volume held on each exchange
+pub type ConsolidatedLevel = (Price, Volume, Vec<(Exchange, Volume)>);
+
+// The best bid and best ask on a single exchange
+pub type VenueTop = (Exchange, Option<(Price, Volume)>, Option<(Price, Volume)>);
+
+// A broadcast from an exchange handler, with the time the message it came from was received
+pub type Stamped = (Broadcast, Timestamp);
+
+#[derive(Debug, Serialize, PartialEq)]
 #[serde(rename_all = "camelCase")]
 pub enum Broadcast {
     #[serde(rename = "hb")]
@@ -30,15 +53,67 @@ pub enum Broadcast {
         bids: Vec<(Price, Volume)>,
         asks: Vec<(Price, Volume)>
     },
+    // The book no longer matches the exchange and should be discarded until the next snapshot arrives
+    OrderbookInvalidated {
+        source: Exchange,
+        pair: CurrencyPair,
+        ts: Timestamp
+    },
+    // Individual orders on each side, with orders at the same price in queue order
+    OrderSnapshot {
+        source: Exchange,
+        pair: CurrencyPair,
+        bids: Vec<Order>,
+        asks: Vec<Order>
+    },
+    OrderAdded {
+        source: Exchange,
+        pair: CurrencyPair,
+        side: Side,
+        order: Order
+    },
+    // The order keeps its ID but may have a new price or volume
+    OrderModified {
+        source: Exchange,
+        pair: CurrencyPair,
+        side: Side,
+        order: Order
+    },
+    // Holds the last known state of the order
+    OrderCancelled {
+        source: Exchange,
+        pair: CurrencyPair,
+        side: Side,
+        order: Order
+    },
+    ConsolidatedOrderbookSnapshot {
+        pair: CurrencyPair,
+        bids: Vec<ConsolidatedLevel>,
+        asks: Vec<ConsolidatedLevel>
+    },
+    // Levels that have changed - a level with no volume has been removed from every exchange
+    ConsolidatedOrderbookUpdate {
+        pair: CurrencyPair,
+        bids: Vec<ConsolidatedLevel>,
+        asks: Vec<ConsolidatedLevel>
+    },
+    // Best prices across every exchange, along with the exchange holding each and the top of every exchange
+    BestBidOffer {
+        pair: CurrencyPair,
+        bid: Option<(Exchange, Price, Volume)>,
+        ask: Option<(Exchange, Price, Volume)>,
+        venues: Vec<VenueTop>,
+        ts: Timestamp
+    },
     TradeSnapshot {
         source: Exchange,
         pair: CurrencyPair,
-        trades: Vec<(Timestamp, Price, Volume, Total)>
+        trades: Vec<TradeDetails>
     },
     Trade {
         source: Exchange,
         pair: CurrencyPair,
-        trade: (Timestamp, Price, Volume, Total)
+        trade: TradeDetails
     },
     Connected {
         multiplier: i32
@@ -50,9 +125,278 @@ pub enum Broadcast {
     ExchangeConnectionClosed {
         exchange: Exchange,
         ts: Timestamp
+    },
+    // Failures counts consecutive failed connection attempts and retry is the milliseconds until the next attempt
+    ExchangeStatus {
+        exchange: Exchange,
+        state: ConnectionState,
+        failures: u32,
+        retry: Option<i64>,
+        ts: Timestamp
+    },
+    // Nothing has been heard from the exchange for the given number of milliseconds
+    // The connection is dropped and a closed message follows
+    ExchangeFeedStale {
+        exchange: Exchange,
+        silence: i64,
+        ts: Timestamp
+    },
+    Subscribed {
+        subscription: Subscription
+    },
+    Unsubscribed {
+        subscription: Subscription
+    },
+    // Acknowledges a change of encoding, and is the first message sent in the new one
+    Encoding {
+        encoding: Encoding
+    },
+    // The client fell too far behind receiving messages, so it is about to be disconnected,
+    // or some of the updates it missed were dropped and the snapshots that follow replace them
+    SlowClient {
+        policy: SlowClientPolicy,
+        limit: usize,
+        message: String
+    },
+    Error {
+        message: String
     }
 }
 
+impl Broadcast {
+    // Ties on price go to the exchange with the most volume at that price
+    pub fn best_bid_offer(pair: CurrencyPair, venues: Vec<VenueTop>, ts: Timestamp) -> Broadcast {
+        let bid = venues.iter()
+            .filter_map(|&(exchange, bid, _)| bid.map(|(price, volume)| (exchange, price, volume)))
+            .max_by_key(|&(_, price, volume)| (price, volume));
+        let ask = venues.iter()
+            .filter_map(|&(exchange, _, ask)| ask.map(|(price, volume)| (exchange, price, volume)))
+            .min_by_key(|&(_, price, volume)| (price, -volume));
+
+        Broadcast::BestBidOffer { pair, bid, ask, venues, ts }
+    }
+
+    // The channel, exchange and pair a broadcast is routed by
+    // Broadcasts without a topic are sent directly to clients rather than through subscriptions
+    pub fn topic(&self) -> Option<(Channel, Option<Exchange>, Option<&CurrencyPair>)> {
+        match *self {
+            Broadcast::OrderbookUpdate { source, ref pair, .. } |
+            Broadcast::OrderbookRemove { source, ref pair, .. } |
+            Broadcast::OrderbookSnapshot { source, ref pair, .. } |
+            Broadcast::OrderbookInvalidated { source, ref pair, .. } =>
+                Some((Channel::Book, Some(source), Some(pair))),
+            Broadcast::OrderSnapshot { source, ref pair, .. } |
+            Broadcast::OrderAdded { source, ref pair, .. } |
+            Broadcast::OrderModified { source, ref pair, .. } |
+            Broadcast::OrderCancelled { source, ref pair, .. } =>
+                Some((Channel::Orders, Some(source), Some(pair))),
+            Broadcast::ConsolidatedOrderbookSnapshot { ref pair, .. } |
+            Broadcast::ConsolidatedOrderbookUpdate { ref pair, .. } =>
+                Some((Channel::ConsolidatedBook, None, Some(pair))),
+            Broadcast::BestBidOffer { ref pair, .. } =>
+                Some((Channel::BestBidOffer, None, Some(pair))),
+            Broadcast::TradeSnapshot { source, ref pair, .. } |
+            Broadcast::Trade { source, ref pair, .. } =>
+                Some((Channel::Trades, Some(source), Some(pair))),
+            Broadcast::ExchangeConnectionOpened { exchange, .. } |
+            Broadcast::ExchangeConnectionClosed { exchange, .. } |
+            Broadcast::ExchangeStatus { exchange, .. } |
+            Broadcast::ExchangeFeedStale { exchange, .. } =>
+                Some((Channel::Connection, Some(exchange), None)),
+            Broadcast::Heartbeat {} |
+            Broadcast::Connected { .. } |
+            Broadcast::Subscribed { .. } |
+            Broadcast::Unsubscribed { .. } |
+            Broadcast::Encoding { .. } |
+            Broadcast::SlowClient { .. } |
+            Broadcast::Error { .. } => None
+        }
+    }
+}
+
+// Broadcasts routed by topic are sent with the sequence number of their stream and the time the server sent them
+// Clients can detect a missed message from a gap in the sequence and request a fresh snapshot
+// Throttled subscriptions number what they send on their own, without gaps for the changes they gather up
+#[derive(Debug, Serialize)]
+pub struct Sequenced<'a> {
+    #[serde(flatten)]
+    pub broadcast: &'a Broadcast,
+    pub seq: u64,
+    pub ts: Timestamp
+}
+
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, PartialEq, Eq, Hash)]
+#[serde(rename_all = "camelCase")]
+pub enum Channel {
+    Book,
+    // Individual orders, for exchanges that report them
+    Orders,
+    Trades,
+    Connection,
+    ConsolidatedBook,
+    BestBidOffer
+}
+
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum ConnectionState {
+    Connecting,
+    Open,
+    // Too many consecutive connection attempts have failed, though attempts continue at the longest delay
+    Down
+}
+
+// What happens to a client once too many messages sent to it have not been received
+#[derive(Debug, Serialize, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum SlowClientPolicy {
+    Disconnect,
+    // Every update is dropped until the client catches up, and then it is sent snapshots for all of its subscriptions
+    Resync,
+    // Only book updates are dropped, and the client is sent the latest snapshot of each book it missed updates to
+    Conflate
+}
+
+impl FromStr for SlowClientPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.trim().to_lowercase().as_str() {
+            "disconnect" => Ok(SlowClientPolicy::Disconnect),
+            "resync" => Ok(SlowClientPolicy::Resync),
+            "conflate" => Ok(SlowClientPolicy::Conflate),
+            _ => Err("expected one of disconnect, resync or conflate".to_string())
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, PartialEq, Eq, Hash)]
+#[serde(rename_all = "camelCase")]
+pub enum Side {
+    Bid,
+    Ask
+}
+
+// Shortest interval a throttled subscription may ask for, in milliseconds, since the server's timers tick this often
+pub const MIN_THROTTLE_INTERVAL: u64 = 100;
+
+// Leaving out the exchange or pair subscribes to every exchange or pair on the channel
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Subscription {
+    pub channel: Channel,
+    pub exchange: Option<Exchange>,
+    pub pair: Option<CurrencyPair>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub throttle: Option<Throttle>
+}
+
+// Book subscriptions may ask for changes to be gathered up and sent at most once an interval from the local books,
+// rather than receiving every update as it arrives
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Throttle {
+    // Milliseconds between updates
+    pub interval: u64,
+    // Price levels on each side, or the whole book when left out
+    #[serde(default)]
+    pub depth: Option<usize>,
+    #[serde(default)]
+    pub mode: ThrottleMode
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum ThrottleMode {
+    // The top of each book that changed
+    #[default]
+    Snapshot,
+    // The levels that changed since the last update, with levels that left the top of the book removed
+    // The first update for each book is a snapshot
+    Delta
+}
+
+impl Subscription {
+    pub fn validate(&self) -> Result<(), String> {
+        match (self.channel, self.exchange) {
+            (Channel::ConsolidatedBook, Some(_)) | (Channel::BestBidOffer, Some(_)) =>
+                return Err(format!("{:?} subscriptions cover every exchange and cannot name one", self.channel)),
+            _ => ()
+        }
+
+        match (self.channel, &self.throttle) {
+            (Channel::Book, &Some(ref throttle)) | (Channel::ConsolidatedBook, &Some(ref throttle)) => {
+                if throttle.interval < MIN_THROTTLE_INTERVAL {
+                    Err(format!("Throttle interval must be at least {} milliseconds", MIN_THROTTLE_INTERVAL))
+                } else if throttle.depth == Some(0) {
+                    Err("Throttle depth must be at least one price level".to_string())
+                } else {
+                    Ok(())
+                }
+            },
+            (_, &Some(_)) => Err(format!("{:?} subscriptions cannot be throttled", self.channel)),
+            (_, &None) => Ok(())
+        }
+    }
+
+    // Subscriptions are told apart by what they cover, whether or not they are throttled
+    pub fn same_topic(&self, other: &Subscription) -> bool {
+        self.channel == other.channel && self.exchange == other.exchange && self.pair == other.pair
+    }
+
+    // Whether the subscription covers an exchange and pair, where a missing one on either side covers all of them
+    pub fn covers(&self, exchange: Option<Exchange>, pair: Option<&CurrencyPair>) -> bool {
+        (self.exchange.is_none() || exchange.is_none() || self.exchange == exchange)
+            && (self.pair.is_none() || pair.is_none() || self.pair.as_ref() == pair)
+    }
+
+    pub fn matches(&self, broadcast: &Broadcast) -> bool {
+        match broadcast.topic() {
+            Some((channel, exchange, pair)) => channel == self.channel && self.covers(exchange, pair),
+            None => false
+        }
+    }
+}
+
+impl fmt::Display for Subscription {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "{:?} for ", self.channel)?;
+        match self.exchange {
+            Some(exchange) => write!(f, "{} ", exchange)?,
+            None => write!(f, "every exchange ")?
+        }
+        match self.pair {
+            Some(ref pair) => write!(f, "{}", pair)?,
+            None => write!(f, "every pair")?
+        }
+        match self.throttle {
+            Some(ref throttle) => write!(f, " every {}ms", throttle.interval),
+            None => Ok(())
+        }
+    }
+}
+
+// Requests that clients may send to the server
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ClientRequest {
+    Subscribe(Subscription),
+    Unsubscribe(Subscription),
+    // Resend the snapshots for a subscription, for example after a gap in sequence numbers
+    Snapshot(Subscription),
+    // Current book for an exchange and pair, optionally limited to the top price levels
+    Book {
+        exchange: Exchange,
+        pair: CurrencyPair,
+        depth: Option<usize>
+    },
+    // Current book merged across every exchange
+    ConsolidatedBook {
+        pair: CurrencyPair,
+        depth: Option<usize>
+    },
+    // Switch the encoding of everything sent to the client from now on
+    Encoding(Encoding)
+}
+
 #[derive(Debug)]
 pub enum BroadcastType {
     None,
@@ -0,0 +1,64 @@
+use std::sync::mpsc;
+use std::thread;
+use ws;
+
+// What to do with a client's connection
+pub enum Command {
+    Send(ws::Message),
+    Ping(Vec<u8>),
+    Close(ws::CloseCode, &'static str),
+    Timeout(u64, ws::util::Token)
+}
+
+// Commands waiting to be handed to the event loop, beyond which posting fails rather than using ever more memory
+const QUEUE_LEN: usize = 65_536;
+
+// Sending on a ws::Sender blocks once the event loop's queue is full, and only the event loop empties that queue
+// So neither the event loop nor anything holding the clients lock sends on one directly, since that can deadlock
+// the server. Instead everything for every client goes through one queue, in the order it was posted, to a thread
+// that does the sending
+// The queue is shared rather than kept per client because it only holds commands until the event loop takes them,
+// which it does promptly unless it is stalled. What builds up for a slow client is its connection's write buffer,
+// which ws does not expose, so ClientSender estimates each client's backlog from pings instead
+#[derive(Clone)]
+pub struct Outbox {
+    tx: mpsc::SyncSender<(ws::Sender, Command)>
+}
+
+impl Outbox {
+    pub fn start() -> Self {
+        let (tx, rx) = mpsc::sync_channel::<(ws::Sender, Command)>(QUEUE_LEN);
+        thread::spawn(move || {
+            for (sender, command) in rx.iter() {
+                let result = match command {
+                    Command::Send(msg) => sender.send(msg),
+                    Command::Ping(payload) => sender.ping(payload),
+                    Command::Close(code, reason) => sender.close_with_reason(code, reason),
+                    Command::Timeout(ms, token) => sender.timeout(ms, token)
+                };
+                if let Err(e) = result {
+                    error!("Could not send to client {}: {}", sender.connection_id(), e);
+                }
+            }
+        });
+        Self { tx }
+    }
+
+    // Never blocks, so it is safe to call from anywhere
+    #[allow(clippy::result_large_err)]
+    pub fn post(&self, sender: &ws::Sender, command: Command) -> ws::Result<()> {
+        self.tx.try_send((sender.clone(), command)).map_err(|e| match e {
+            mpsc::TrySendError::Full(_) => ws::Error::new(ws::ErrorKind::Capacity, "The outbox is full"),
+            mpsc::TrySendError::Disconnected(_) => ws::Error::new(ws::ErrorKind::Internal, "The outbox thread has stopped")
+        })
+    }
+}
+
+#[cfg(test)]
+impl Outbox {
+    // An outbox with nothing sending from it, along with the commands posted to it
+    pub fn detached() -> (Self, mpsc::Receiver<(ws::Sender, Command)>) {
+        let (tx, rx) = mpsc::sync_channel(QUEUE_LEN);
+        (Self { tx }, rx)
+    }
+}
\ No newline at end of file
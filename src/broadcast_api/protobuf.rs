@@ -0,0 +1,218 @@
+use super::{Broadcast, Channel, ConnectionState, ConsolidatedLevel, Order, Price, Side, SlowClientPolicy, Subscription,
+            ThrottleMode, Timestamp, TradeDetails, Volume};
+use super::encoding::Encoding;
+use domain::{CurrencyPair, Exchange};
+use prost::Message;
+
+// Types generated from proto/aggregator/broadcast/v1/broadcast.proto by the build script
+pub mod schema {
+    include!(concat!(env!("OUT_DIR"), "/aggregator.broadcast.v1.rs"));
+}
+
+use self::schema::broadcast::Body;
+
+// Messages that can be sent to clients using the protobuf encoding
+pub trait ToProtobuf {
+    fn to_protobuf(&self) -> schema::Broadcast;
+}
+
+pub fn encode<T: ToProtobuf>(value: &T) -> Vec<u8> {
+    let message = value.to_protobuf();
+    let mut buf = Vec::with_capacity(message.encoded_len());
+    message.encode(&mut buf).expect("Buffer was too small for protobuf message - this should never happen!");
+    buf
+}
+
+// The sequence number and send time are only given for broadcasts routed by topic
+pub fn broadcast(broadcast: &Broadcast, seq: Option<u64>, ts: Option<Timestamp>) -> schema::Broadcast {
+    let body = match *broadcast {
+        Broadcast::Heartbeat {} => Body::Heartbeat(schema::Heartbeat {}),
+        Broadcast::OrderbookUpdate { source, ref pair, ref bids, ref asks } =>
+            Body::OrderbookUpdate(orderbook(source, pair, bids, asks)),
+        Broadcast::OrderbookRemove { source, ref pair, ref bids, ref asks } =>
+            Body::OrderbookRemove(orderbook(source, pair, bids, asks)),
+        Broadcast::OrderbookSnapshot { source, ref pair, ref bids, ref asks } =>
+            Body::OrderbookSnapshot(orderbook(source, pair, bids, asks)),
+        Broadcast::OrderbookInvalidated { source, ref pair, ts } =>
+            Body::OrderbookInvalidated(schema::OrderbookInvalidated { source: exchange(source), pair: Some(currency_pair(pair)), ts }),
+        Broadcast::OrderSnapshot { source, ref pair, ref bids, ref asks } => Body::OrderSnapshot(schema::OrderSnapshot {
+            source: exchange(source),
+            pair: Some(currency_pair(pair)),
+            bids: bids.iter().map(order).collect(),
+            asks: asks.iter().map(order).collect()
+        }),
+        Broadcast::OrderAdded { source, ref pair, side, ref order } =>
+            Body::OrderAdded(order_change(source, pair, side, order)),
+        Broadcast::OrderModified { source, ref pair, side, ref order } =>
+            Body::OrderModified(order_change(source, pair, side, order)),
+        Broadcast::OrderCancelled { source, ref pair, side, ref order } =>
+            Body::OrderCancelled(order_change(source, pair, side, order)),
+        Broadcast::ConsolidatedOrderbookSnapshot { ref pair, ref bids, ref asks } =>
+            Body::ConsolidatedOrderbookSnapshot(consolidated_orderbook(pair, bids, asks)),
+        Broadcast::ConsolidatedOrderbookUpdate { ref pair, ref bids, ref asks } =>
+            Body::ConsolidatedOrderbookUpdate(consolidated_orderbook(pair, bids, asks)),
+        Broadcast::BestBidOffer { ref pair, bid, ask, ref venues, ts } => Body::BestBidOffer(schema::BestBidOffer {
+            pair: Some(currency_pair(pair)),
+            bid: bid.map(best_price),
+            ask: ask.map(best_price),
+            venues: venues.iter().map(|&(venue, bid, ask)| schema::VenueTop {
+                exchange: exchange(venue),
+                bid: bid.map(level),
+                ask: ask.map(level)
+            }).collect(),
+            ts
+        }),
+        Broadcast::TradeSnapshot { source, ref pair, ref trades } => Body::TradeSnapshot(schema::TradeSnapshot {
+            source: exchange(source),
+            pair: Some(currency_pair(pair)),
+            trades: trades.iter().map(|&details| trade(details)).collect()
+        }),
+        Broadcast::Trade { source, ref pair, trade: details } => Body::Trade(schema::TradeUpdate {
+            source: exchange(source),
+            pair: Some(currency_pair(pair)),
+            trade: Some(trade(details))
+        }),
+        Broadcast::Connected { multiplier } => Body::Connected(schema::Connected { multiplier }),
+        Broadcast::ExchangeConnectionOpened { exchange: opened, ts } =>
+            Body::ExchangeConnectionOpened(schema::ExchangeConnection { exchange: exchange(opened), ts }),
+        Broadcast::ExchangeConnectionClosed { exchange: closed, ts } =>
+            Body::ExchangeConnectionClosed(schema::ExchangeConnection { exchange: exchange(closed), ts }),
+        Broadcast::ExchangeStatus { exchange: status, state, failures, retry, ts } => Body::ExchangeStatus(schema::ExchangeStatus {
+            exchange: exchange(status),
+            state: connection_state(state),
+            failures,
+            retry,
+            ts
+        }),
+        Broadcast::ExchangeFeedStale { exchange: stale, silence, ts } =>
+            Body::ExchangeFeedStale(schema::ExchangeFeedStale { exchange: exchange(stale), silence, ts }),
+        Broadcast::Subscribed { ref subscription } => Body::Subscribed(self::subscription(subscription)),
+        Broadcast::Unsubscribed { ref subscription } => Body::Unsubscribed(self::subscription(subscription)),
+        Broadcast::Encoding { encoding } => Body::Encoding(self::encoding(encoding)),
+        Broadcast::SlowClient { policy, limit, ref message } => Body::SlowClient(schema::SlowClient {
+            policy: slow_client_policy(policy),
+            limit: limit as u64,
+            message: message.clone()
+        }),
+        Broadcast::Error { ref message } => Body::Error(schema::Error { message: message.clone() })
+    };
+
+    schema::Broadcast { seq, ts, body: Some(body) }
+}
+
+fn orderbook(source: Exchange, pair: &CurrencyPair, bids: &[(Price, Volume)], asks: &[(Price, Volume)]) -> schema::Orderbook {
+    schema::Orderbook {
+        source: exchange(source),
+        pair: Some(currency_pair(pair)),
+        bids: bids.iter().map(|&entry| level(entry)).collect(),
+        asks: asks.iter().map(|&entry| level(entry)).collect()
+    }
+}
+
+fn order_change(source: Exchange, pair: &CurrencyPair, side: Side, &details: &Order) -> schema::OrderChange {
+    schema::OrderChange {
+        source: exchange(source),
+        pair: Some(currency_pair(pair)),
+        side: match side {
+            Side::Bid => schema::Side::Bid,
+            Side::Ask => schema::Side::Ask
+        } as i32,
+        order: Some(order(&details))
+    }
+}
+
+fn consolidated_orderbook(pair: &CurrencyPair, bids: &[ConsolidatedLevel], asks: &[ConsolidatedLevel]) -> schema::ConsolidatedOrderbook {
+    let consolidated_level = |&(price, volume, ref exchanges): &ConsolidatedLevel| schema::ConsolidatedLevel {
+        price,
+        volume,
+        exchanges: exchanges.iter()
+            .map(|&(held_by, volume)| schema::ExchangeVolume { exchange: exchange(held_by), volume })
+            .collect()
+    };
+
+    schema::ConsolidatedOrderbook {
+        pair: Some(currency_pair(pair)),
+        bids: bids.iter().map(&consolidated_level).collect(),
+        asks: asks.iter().map(&consolidated_level).collect()
+    }
+}
+
+fn level((price, volume): (Price, Volume)) -> schema::Level {
+    schema::Level { price, volume }
+}
+
+fn order(&(id, price, volume): &Order) -> schema::Order {
+    schema::Order { id, price, volume }
+}
+
+fn trade((ts, price, volume, total): TradeDetails) -> schema::Trade {
+    schema::Trade { ts, price, volume, total }
+}
+
+fn best_price((held_by, price, volume): (Exchange, Price, Volume)) -> schema::BestPrice {
+    schema::BestPrice { exchange: exchange(held_by), price, volume }
+}
+
+fn currency_pair(pair: &CurrencyPair) -> schema::CurrencyPair {
+    schema::CurrencyPair { base: pair.base.clone(), quote: pair.quote.clone() }
+}
+
+fn subscription(subscription: &Subscription) -> schema::Subscription {
+    schema::Subscription {
+        channel: match subscription.channel {
+            Channel::Book => schema::Channel::Book,
+            Channel::Orders => schema::Channel::Orders,
+            Channel::Trades => schema::Channel::Trades,
+            Channel::Connection => schema::Channel::Connection,
+            Channel::ConsolidatedBook => schema::Channel::ConsolidatedBook,
+            Channel::BestBidOffer => schema::Channel::BestBidOffer
+        } as i32,
+        exchange: subscription.exchange.map(exchange),
+        pair: subscription.pair.as_ref().map(currency_pair),
+        throttle: subscription.throttle.as_ref().map(|throttle| schema::Throttle {
+            interval: throttle.interval,
+            depth: throttle.depth.map(|depth| depth as u64),
+            mode: match throttle.mode {
+                ThrottleMode::Snapshot => schema::ThrottleMode::Snapshot,
+                ThrottleMode::Delta => schema::ThrottleMode::Delta
+            } as i32
+        })
+    }
+}
+
+fn exchange(exchange: Exchange) -> i32 {
+    let mapped = match exchange {
+        Exchange::BtcMarkets => schema::Exchange::BtcMarkets,
+        Exchange::Bitfinex => schema::Exchange::Bitfinex,
+        Exchange::Poloniex => schema::Exchange::Poloniex
+    };
+    mapped as i32
+}
+
+fn connection_state(state: ConnectionState) -> i32 {
+    let mapped = match state {
+        ConnectionState::Connecting => schema::ConnectionState::Connecting,
+        ConnectionState::Open => schema::ConnectionState::Open,
+        ConnectionState::Down => schema::ConnectionState::Down
+    };
+    mapped as i32
+}
+
+fn encoding(encoding: Encoding) -> i32 {
+    let mapped = match encoding {
+        Encoding::Json => schema::Encoding::Json,
+        Encoding::MessagePack => schema::Encoding::Msgpack,
+        Encoding::Cbor => schema::Encoding::Cbor,
+        Encoding::Protobuf => schema::Encoding::Protobuf
+    };
+    mapped as i32
+}
+
+fn slow_client_policy(policy: SlowClientPolicy) -> i32 {
+    let mapped = match policy {
+        SlowClientPolicy::Disconnect => schema::SlowClientPolicy::Disconnect,
+        SlowClientPolicy::Resync => schema::SlowClientPolicy::Resync,
+        SlowClientPolicy::Conflate => schema::SlowClientPolicy::Conflate
+    };
+    mapped as i32
+}
\ No newline at end of file
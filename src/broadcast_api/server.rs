@@ -1,18 +1,35 @@
-use super::Broadcast;
+use super::{Broadcast, Channel, ClientRequest, Stamped, Subscription};
+use super::clients::{self, ClientSender, Clients};
+use super::deflate::Deflate;
+use super::encoding::Encoding;
+use super::outbox::Outbox;
 
+use config::{CompressionConfig, SlowClientConfig};
+use domain::{CurrencyPair, Exchange, MarketState, StatusStore};
+use metrics::Metrics;
+use std::io;
 use std::thread;
-use std::str::FromStr;
+use std::net::SocketAddr;
+use std::sync::{Arc, mpsc, atomic::{AtomicBool, Ordering}};
 use ws;
 
 pub struct Server {
-    // Channel that funnels broadcasts to the WebSocket
-    tx: ws::Sender,
-    // Store the heartbeat to prevent reserializing it
-    hb: String
+    // Channel that funnels broadcasts to the clients subscribed to them
+    broadcast_tx: mpsc::Sender<Stamped>,
+    // Every client connected to the WebSocket
+    clients: Clients,
+    // Whether the WebSocket is bound and accepting connections
+    listening: Arc<AtomicBool>
 }
 
 impl Server {
-    pub fn run() -> Self {
+    pub fn run(addr: SocketAddr, compression: Option<CompressionConfig>, slow_clients: SlowClientConfig, state: MarketState,
+               status: StatusStore, metrics: Metrics) -> Self {
+
+        let clients = Clients::new(slow_clients, state.clone());
+        // Clients are pinged often enough to notice they are falling behind well before reaching the limit
+        let ping_every = slow_clients.max_queued / 4;
+        let outbox = Outbox::start();
 
         let server = ws::Builder::new().with_settings({
             let mut settings = ws::Settings::default();
@@ -20,50 +37,316 @@ impl Server {
             settings.panic_on_internal = false;
             settings.panic_on_new_connection = true;
             settings
-        }).build(move |out: ws::Sender| {
-            info!("Client has connected to the server");
+        }).build({
+            let clients = clients.clone();
+            move |out: ws::Sender| {
+                ClientHandler {
+                    out: ClientSender::new(out, outbox.clone(), ping_every),
+                    encoding: Encoding::default(),
+                    compression,
+                    deflate: None,
+                    metrics: metrics.clone(),
+                    clients: clients.clone(),
+                    state: state.clone(),
+                    status: status.clone()
+                }
+            }
+        }).expect("Could not create WebSocket broadcast server!");
+
+        // Kick off a thread with our running server inside it
+        let listening = Arc::new(AtomicBool::new(false));
+        thread::spawn({
+            let listening = listening.clone();
+            move || {
+                match server.bind(addr) {
+                    Ok(server) => {
+                        listening.store(true, Ordering::SeqCst);
+                        match server.run() {
+                            Ok(_) => info!("Broadcast listen socket closed gracefully"),
+                            Err(e) => error!("Broadcast listen socket ended in an error: {}", e)
+                        }
+                    },
+                    Err(e) => error!("Could not bind broadcast listen socket to {}: {}", addr, e)
+                }
+                listening.store(false, Ordering::SeqCst);
+            }
+        });
+
+        // Route broadcasts from the exchange handlers to subscribed clients
+        let (broadcast_tx, broadcast_rx) = mpsc::channel();
+        thread::spawn({
+            let clients = clients.clone();
+            move || {
+                for (broadcast, ts) in broadcast_rx.iter() {
+                    clients.dispatch(broadcast, ts);
+                }
+            }
+        });
+
+        Self { broadcast_tx, clients, listening }
+    }
+
+    pub fn heartbeat(&self) {
+        self.clients.send_all(&Broadcast::Heartbeat {});
+    }
+
+    pub fn tx(&self) -> mpsc::Sender<Stamped> {
+        self.broadcast_tx.clone()
+    }
 
-            // Broadcast a connected message to clients when they hook into the broadcast API
-            let connected = Broadcast::Connected { multiplier: ::MULTIPLIER };
-            let connected_serialized = ::serde_json::to_string(&connected)
-                .expect("Could not serialize connection message - this should never happen!");
+    pub fn clients(&self) -> Clients {
+        self.clients.clone()
+    }
+
+    pub fn listening(&self) -> Arc<AtomicBool> {
+        self.listening.clone()
+    }
+}
 
-            out.send(connected_serialized)
-                .unwrap_or_else(|e| error!("Could not send connected message to client: {}", e));
+struct ClientHandler {
+    out: ClientSender,
+    // Only used to decode binary requests, since the clients are what send with it
+    encoding: Encoding,
+    // Settings for permessage-deflate when it is enabled, and the extension if the client negotiated it
+    compression: Option<CompressionConfig>,
+    deflate: Option<Deflate>,
+    metrics: Metrics,
+    clients: Clients,
+    state: MarketState,
+    status: StatusStore
+}
 
-            move |msg| {
-                debug!("Got message from client: {}", msg);
+impl ws::Handler for ClientHandler {
+    // Clients may ask for an encoding as a subprotocol, and the first one we support is used
+    fn on_request(&mut self, req: &ws::Request) -> ws::Result<ws::Response> {
+        let mut response = ws::Response::from_request(req)?;
 
-                // If handling messages from clients is needed, this should be done here
+        let negotiated = req.protocols()?.into_iter()
+            .filter_map(|protocol| Encoding::from_protocol(protocol).map(|encoding| (protocol, encoding)))
+            .next();
+        if let Some((protocol, encoding)) = negotiated {
+            response.set_protocol(protocol);
+            self.encoding = encoding;
+        }
 
-                Ok(())
+        if let Some(ref compression) = self.compression {
+            if let Some((deflate, extension)) = Deflate::negotiate(compression, &req.extensions()?) {
+                response.add_extension(&extension);
+                self.deflate = Some(deflate);
             }
-        }).expect("Could not create WebSocket broadcast server!");
+        }
 
-        let tx = server.broadcaster().clone();
+        Ok(response)
+    }
 
-        // Kick off a thread with our running server inside it
-        thread::spawn(move || {
-            let addr = dotenv!("SERVER_ADDR");
-            let socket_addr = ::std::net::SocketAddr::from_str(dotenv!("SERVER_ADDR"))
-                .expect(&format!("Could not establish server on {} - recheck the environment file values", &addr));
-            match server.listen(&socket_addr) {
-                Ok(_) => info!("Broadcast listen socket closed gracefully"),
-                Err(e) => error!("Broadcast listen socket ended in an error: {}", e)
+    fn on_open(&mut self, _: ws::Handshake) -> ws::Result<()> {
+        info!("Client has connected to the server using {:?}{}", self.encoding,
+              if self.deflate.is_some() { " with compression" } else { "" });
+
+        self.clients.add(self.out.clone(), self.encoding);
+
+        // Broadcast a connected message to clients when they hook into the broadcast API
+        self.clients.respond(self.out.connection_id(), || vec!(Broadcast::Connected { multiplier: ::MULTIPLIER }));
+
+        Ok(())
+    }
+
+    fn on_message(&mut self, msg: ws::Message) -> ws::Result<()> {
+        debug!("Got message from client: {}", msg);
+
+        let response = match self.encoding.decode::<ClientRequest>(&msg) {
+            Ok(request) => self.handle_request(request),
+            Err(e) => Some(Broadcast::Error { message: format!("Could not parse request: {}", e) })
+        };
+
+        if let Some(response) = response {
+            self.clients.respond(self.out.connection_id(), || vec!(response));
+        }
+
+        Ok(())
+    }
+
+    fn on_frame(&mut self, mut frame: ws::Frame) -> ws::Result<Option<ws::Frame>> {
+        if let Some(ref mut deflate) = self.deflate {
+            deflate.decompress(&mut frame).map_err(|e|
+                ws::Error::new(ws::ErrorKind::Protocol, format!("Could not decompress frame: {}", e)))?;
+        }
+
+        // Only permessage-deflate uses a reserved bit, and it has been cleared once the frame is decompressed
+        if frame.has_rsv1() || frame.has_rsv2() || frame.has_rsv3() {
+            return Err(ws::Error::new(ws::ErrorKind::Protocol, "Encountered frame with reserved bits set."));
+        }
+
+        // Pongs echo the count of messages sent before each ping, confirming the client has received them
+        if frame.opcode() == ws::OpCode::Pong {
+            self.out.acknowledge(frame.payload());
+            self.clients.caught_up(self.out.connection_id(), |subscription| self.snapshots(subscription));
+        }
+
+        Ok(Some(frame))
+    }
+
+    // Messages are always sent whole, and fragmented afterwards if they are too long for a single frame
+    fn on_send_frame(&mut self, mut frame: ws::Frame) -> ws::Result<Option<ws::Frame>> {
+        match frame.opcode() {
+            ws::OpCode::Text | ws::OpCode::Binary => {
+                if let Some(ref mut deflate) = self.deflate {
+                    let compressed = deflate.compress(frame.payload()).map_err(|e|
+                        ws::Error::new(ws::ErrorKind::Internal, format!("Could not compress frame: {}", e)))?;
+                    self.metrics.message_compressed(frame.payload().len(), compressed.len());
+
+                    *frame.payload_mut() = compressed;
+                    frame.set_rsv1(true);
+                }
+            },
+            _ => ()
+        }
+        Ok(Some(frame))
+    }
+
+    // A slow client that was disconnected has not finished closing in time, so the connection is dropped
+    // Every other timeout is for the next update of a throttled subscription
+    fn on_timeout(&mut self, event: ws::util::Token) -> ws::Result<()> {
+        if event == clients::CLOSE_TIMEOUT {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "Slow client did not close in time").into());
+        }
+        self.clients.flush(self.out.connection_id(), event);
+        Ok(())
+    }
+
+    fn on_close(&mut self, _code: ws::CloseCode, reason: &str) {
+        info!("Client has disconnected from the server: {}", reason);
+        self.clients.remove(self.out.connection_id());
+    }
+}
+
+impl ClientHandler {
+    // Returns the response to send to the client, if it has not already been sent
+    fn handle_request(&mut self, request: ClientRequest) -> Option<Broadcast> {
+        match request {
+            ClientRequest::Subscribe(subscription) => self.subscribe(subscription),
+            ClientRequest::Unsubscribe(subscription) => Some(self.unsubscribe(subscription)),
+            ClientRequest::Snapshot(subscription) => {
+                if let Err(message) = subscription.validate() {
+                    return Some(Broadcast::Error { message });
+                }
+                if !self.clients.resend_throttled(self.out.connection_id(), &subscription) {
+                    self.clients.respond(self.out.connection_id(), || self.snapshots(&subscription));
+                }
+                None
+            },
+            ClientRequest::Book { exchange, pair, depth } => {
+                self.clients.respond(self.out.connection_id(), || vec!(match self.state.books.book(exchange, &pair) {
+                    Some(book) => {
+                        let (bids, asks) = book.top(depth.unwrap_or(usize::MAX));
+                        Broadcast::OrderbookSnapshot { source: exchange, pair, bids, asks }
+                    },
+                    None => Broadcast::Error { message: format!("No {} orderbook is available for {}", exchange, pair) }
+                }));
+                None
+            },
+            ClientRequest::ConsolidatedBook { pair, depth } => {
+                self.clients.respond(self.out.connection_id(), || {
+                    let (mut bids, mut asks) = self.state.books.consolidated(&pair);
+                    let depth = depth.unwrap_or(usize::MAX);
+                    bids.truncate(depth);
+                    asks.truncate(depth);
+                    vec!(Broadcast::ConsolidatedOrderbookSnapshot { pair, bids, asks })
+                });
+                None
+            },
+            ClientRequest::Encoding(encoding) => {
+                self.encoding = encoding;
+                self.clients.set_encoding(self.out.connection_id(), encoding);
+                None
             }
-        });
+        }
+    }
+
+    fn subscribe(&mut self, subscription: Subscription) -> Option<Broadcast> {
+        if let Err(message) = subscription.validate() {
+            return Some(Broadcast::Error { message });
+        }
 
-        let hb = ::serde_json::to_string(&Broadcast::Heartbeat {})
-            .expect("Could not serialize heartbeat - this should never happen!");
+        let id = self.out.connection_id();
+        let subscribed = if subscription.throttle.is_some() {
+            self.clients.subscribe_throttled(id, subscription.clone())
+        } else {
+            self.clients.subscribe(id, subscription.clone(), |subscription| self.snapshots(subscription))
+        };
 
-        Self { tx, hb }
+        if subscribed {
+            None
+        } else {
+            Some(Broadcast::Error { message: format!("Already subscribed to {}", subscription) })
+        }
     }
 
-    pub fn heartbeat(&self) {
-        self.tx.send((&self.hb).as_str()).unwrap_or_else(|e| error!("Could not send heartbeat: {}", e));
+    fn unsubscribe(&mut self, subscription: Subscription) -> Broadcast {
+        if self.clients.unsubscribe(self.out.connection_id(), &subscription) {
+            Broadcast::Unsubscribed { subscription }
+        } else {
+            Broadcast::Error { message: format!("Not subscribed to {}", subscription) }
+        }
+    }
+
+    // Current state of everything covered by a subscription, so clients can apply the updates that follow
+    fn snapshots(&self, subscription: &Subscription) -> Vec<Broadcast> {
+        let covers = |&(exchange, ref pair): &(Exchange, CurrencyPair)| {
+            subscription.exchange.is_none_or(|subscribed| subscribed == exchange)
+                && subscription.pair.as_ref().is_none_or(|subscribed| subscribed == pair)
+        };
+
+        match subscription.channel {
+            Channel::Book => self.state.books.keys().into_iter()
+                .filter(|key| covers(key))
+                .filter_map(|(exchange, pair)| self.state.books.book(exchange, &pair).map(|book| {
+                    let (bids, asks) = book.top(usize::MAX);
+                    Broadcast::OrderbookSnapshot { source: exchange, pair, bids, asks }
+                }))
+                .collect(),
+            Channel::Orders => self.state.orders.keys().into_iter()
+                .filter(|key| covers(key))
+                .filter_map(|(exchange, pair)| self.state.orders.orders(exchange, &pair).map(|orders| {
+                    let (bids, asks) = orders.ranked();
+                    Broadcast::OrderSnapshot { source: exchange, pair, bids, asks }
+                }))
+                .collect(),
+            Channel::Trades => self.state.trades.keys().into_iter()
+                .filter(|key| covers(key))
+                .map(|(exchange, pair)| {
+                    let trades = self.state.trades.recent(exchange, &pair);
+                    Broadcast::TradeSnapshot { source: exchange, pair, trades }
+                })
+                .collect(),
+            Channel::ConsolidatedBook => self.pairs(subscription).into_iter()
+                .map(|pair| {
+                    let (bids, asks) = self.state.books.consolidated(&pair);
+                    Broadcast::ConsolidatedOrderbookSnapshot { pair, bids, asks }
+                })
+                .collect(),
+            Channel::BestBidOffer => self.pairs(subscription).into_iter()
+                .map(|pair| {
+                    let venues = self.state.books.tops(&pair);
+                    Broadcast::best_bid_offer(pair, venues, ::consumer::timestamp())
+                })
+                .collect(),
+            Channel::Connection => self.status.all().into_iter()
+                .filter(|&(exchange, _, _)| subscription.exchange.is_none_or(|subscribed| subscribed == exchange))
+                .map(|(exchange, state, failures)|
+                    Broadcast::ExchangeStatus { exchange, state, failures, retry: None, ts: ::consumer::timestamp() })
+                .collect()
+        }
     }
 
-    pub fn tx(&self) -> ws::Sender {
-        self.tx.clone()
+    // Pairs with a book on any exchange that are covered by a subscription
+    fn pairs(&self, subscription: &Subscription) -> Vec<CurrencyPair> {
+        let mut pairs: Vec<CurrencyPair> = self.state.books.keys().into_iter()
+            .map(|(_, pair)| pair)
+            .filter(|pair| subscription.pair.as_ref().is_none_or(|subscribed| subscribed == pair))
+            .collect();
+        pairs.sort_unstable();
+        pairs.dedup();
+        pairs
     }
 }
\ No newline at end of file
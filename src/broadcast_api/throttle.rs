@@ -0,0 +1,296 @@
+use super::{Broadcast, Channel, ConsolidatedLevel, Price, Subscription, Throttle, ThrottleMode, Volume};
+use super::clients::StreamKey;
+
+use domain::BookStore;
+use std::collections::{HashMap, HashSet};
+use ws;
+
+// Books sent to a client for one of its throttled subscriptions, and which of them have changed since
+// The client only sees some of the changes to each book, so what it is sent is numbered separately from the
+// unthrottled stream, and each update moves the book's sequence on by one
+pub struct Throttled {
+    pub subscription: Subscription,
+    // Timeout the client's connection uses for the next update
+    pub token: ws::util::Token,
+    throttle: Throttle,
+    // Books with changes that have not been sent yet
+    changed: HashSet<StreamKey>,
+    // Set when every book covered by the subscription needs sending, such as after subscribing
+    everything: bool,
+    // Levels last sent for each book in delta mode, which the next delta is worked out against
+    sent: HashMap<StreamKey, Levels>,
+    // Sequence number of the last update sent for each book
+    sequences: HashMap<StreamKey, u64>
+}
+
+enum Levels {
+    Book(Vec<(Price, Volume)>, Vec<(Price, Volume)>),
+    Consolidated(Vec<ConsolidatedLevel>, Vec<ConsolidatedLevel>)
+}
+
+impl Throttled {
+    // Returns None unless the subscription is throttled
+    pub fn new(subscription: Subscription, token: ws::util::Token) -> Option<Self> {
+        let throttle = subscription.throttle.clone()?;
+        Some(Self {
+            subscription,
+            token,
+            throttle,
+            changed: HashSet::new(),
+            everything: true,
+            sent: HashMap::new(),
+            sequences: HashMap::new()
+        })
+    }
+
+    pub fn interval(&self) -> u64 {
+        self.throttle.interval
+    }
+
+    // The next update for every book is a snapshot, whatever the mode
+    pub fn reset(&mut self) {
+        self.sent.clear();
+        self.everything = true;
+    }
+
+    // Notes a broadcast on the subscription's books, returning the sequence number to send it to the client with
+    // if it should still be sent as it is
+    // Invalidations are passed on straight away, since clients should stop using the book rather than wait
+    pub fn note(&mut self, key: &StreamKey, broadcast: &Broadcast) -> Option<u64> {
+        if !self.subscription.matches(broadcast) {
+            return None;
+        }
+
+        match *broadcast {
+            Broadcast::OrderbookSnapshot { .. } |
+            Broadcast::ConsolidatedOrderbookSnapshot { .. } => {
+                self.sent.remove(key);
+            },
+            Broadcast::OrderbookInvalidated { .. } => {
+                self.sent.remove(key);
+                self.changed.remove(key);
+                return Some(self.next_seq(key));
+            },
+            _ => ()
+        }
+
+        self.changed.insert(key.clone());
+        None
+    }
+
+    // Updates for every book that changed since the last time, taken from the local books as they are now,
+    // along with their sequence numbers
+    pub fn flush(&mut self, books: &BookStore) -> Vec<(Broadcast, u64)> {
+        if self.everything {
+            self.everything = false;
+            let subscription = &self.subscription;
+            let covered: Vec<StreamKey> = match subscription.channel {
+                Channel::Book => books.keys().into_iter()
+                    .filter(|(exchange, pair)| subscription.covers(Some(*exchange), Some(pair)))
+                    .map(|(exchange, pair)| (Channel::Book, Some(exchange), Some(pair)))
+                    .collect(),
+                Channel::ConsolidatedBook => books.keys().into_iter()
+                    .filter(|(_, pair)| subscription.covers(None, Some(pair)))
+                    .map(|(_, pair)| (Channel::ConsolidatedBook, None, Some(pair)))
+                    .collect(),
+                _ => vec!()
+            };
+            self.changed.extend(covered);
+        }
+
+        let depth = self.throttle.depth.unwrap_or(usize::MAX);
+        let changed: Vec<StreamKey> = self.changed.drain().collect();
+        let mut broadcasts = vec!();
+
+        for key in changed {
+            let mut updates = vec!();
+            match key {
+                (Channel::Book, Some(source), Some(ref pair)) => match books.book(source, pair) {
+                    Some(book) => {
+                        let (bids, asks) = book.top(depth);
+                        match self.remember(&key, Levels::Book(bids.clone(), asks.clone())) {
+                            Some(Levels::Book(sent_bids, sent_asks)) => {
+                                let (changed_bids, removed_bids) = diff(&sent_bids, &bids, |level| level.0);
+                                let (changed_asks, removed_asks) = diff(&sent_asks, &asks, |level| level.0);
+                                if !removed_bids.is_empty() || !removed_asks.is_empty() {
+                                    updates.push(Broadcast::OrderbookRemove {
+                                        source, pair: pair.clone(), bids: removed_bids, asks: removed_asks
+                                    });
+                                }
+                                if !changed_bids.is_empty() || !changed_asks.is_empty() {
+                                    updates.push(Broadcast::OrderbookUpdate {
+                                        source, pair: pair.clone(), bids: changed_bids, asks: changed_asks
+                                    });
+                                }
+                            },
+                            _ => updates.push(Broadcast::OrderbookSnapshot { source, pair: pair.clone(), bids, asks })
+                        }
+                    },
+                    // The book was dropped, and clients have already been told why
+                    None => {
+                        self.sent.remove(&key);
+                    }
+                },
+                (Channel::ConsolidatedBook, None, Some(ref pair)) => {
+                    let (mut bids, mut asks) = books.consolidated(pair);
+                    bids.truncate(depth);
+                    asks.truncate(depth);
+                    match self.remember(&key, Levels::Consolidated(bids.clone(), asks.clone())) {
+                        Some(Levels::Consolidated(sent_bids, sent_asks)) => {
+                            let (mut changed_bids, removed_bids) = diff(&sent_bids, &bids, |level| level.0);
+                            let (mut changed_asks, removed_asks) = diff(&sent_asks, &asks, |level| level.0);
+                            // Consolidated updates remove a level by giving it no volume
+                            changed_bids.extend(removed_bids.into_iter().map(|(price, _, _)| (price, 0, vec!())));
+                            changed_asks.extend(removed_asks.into_iter().map(|(price, _, _)| (price, 0, vec!())));
+                            if !changed_bids.is_empty() || !changed_asks.is_empty() {
+                                updates.push(Broadcast::ConsolidatedOrderbookUpdate {
+                                    pair: pair.clone(), bids: changed_bids, asks: changed_asks
+                                });
+                            }
+                        },
+                        _ => updates.push(Broadcast::ConsolidatedOrderbookSnapshot { pair: pair.clone(), bids, asks })
+                    }
+                },
+                _ => ()
+            }
+
+            for update in updates {
+                let seq = self.next_seq(&key);
+                broadcasts.push((update, seq));
+            }
+        }
+
+        broadcasts
+    }
+
+    fn next_seq(&mut self, key: &StreamKey) -> u64 {
+        let seq = self.sequences.entry(key.clone()).or_insert(0);
+        *seq += 1;
+        *seq
+    }
+
+    // Keeps the levels being sent in delta mode, returning the ones sent before them
+    fn remember(&mut self, key: &StreamKey, levels: Levels) -> Option<Levels> {
+        match self.throttle.mode {
+            ThrottleMode::Delta => self.sent.insert(key.clone(), levels),
+            ThrottleMode::Snapshot => None
+        }
+    }
+}
+
+// Levels that are new or have changed since they were sent, and sent levels that are no longer there, matched by price
+fn diff<L: Clone + PartialEq, F: Fn(&L) -> Price>(sent: &[L], current: &[L], price: F) -> (Vec<L>, Vec<L>) {
+    let sent_prices: HashMap<Price, &L> = sent.iter().map(|level| (price(level), level)).collect();
+    let current_prices: HashSet<Price> = current.iter().map(&price).collect();
+
+    let changed = current.iter()
+        .filter(|&level| sent_prices.get(&price(level)).is_none_or(|&sent| sent != level))
+        .cloned()
+        .collect();
+    let removed = sent.iter()
+        .filter(|&level| !current_prices.contains(&price(level)))
+        .cloned()
+        .collect();
+
+    (changed, removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::Throttle;
+    use domain::{CurrencyPair, Exchange};
+
+    fn pair() -> CurrencyPair {
+        CurrencyPair::new("XRP", "BTC")
+    }
+
+    fn throttled(mode: ThrottleMode, depth: Option<usize>) -> Throttled {
+        let subscription = Subscription {
+            channel: Channel::Book,
+            exchange: Some(Exchange::Bitfinex),
+            pair: Some(pair()),
+            throttle: Some(Throttle { interval: 100, depth, mode })
+        };
+        Throttled::new(subscription, ws::util::Token(1)).expect("Subscription is throttled")
+    }
+
+    fn set_book(books: &BookStore, bids: &[(Price, Volume)], asks: &[(Price, Volume)]) {
+        books.update(Exchange::Bitfinex, &pair(), |book| book.replace(bids, asks));
+    }
+
+    // The server notes every update to the book, which is held back until the next flush
+    fn changed(throttled: &mut Throttled) {
+        let broadcast = Broadcast::OrderbookUpdate { source: Exchange::Bitfinex, pair: pair(), bids: vec!(), asks: vec!() };
+        let key = (Channel::Book, Some(Exchange::Bitfinex), Some(pair()));
+        assert_eq!(throttled.note(&key, &broadcast), None);
+    }
+
+    #[test]
+    fn diffs_levels_by_price() {
+        let sent = vec!((100, 5), (99, 3), (98, 1));
+        let current = vec!((101, 2), (100, 5), (99, 4));
+
+        let (changed, removed) = diff(&sent, &current, |level| level.0);
+
+        assert_eq!(changed, vec!((101, 2), (99, 4)));
+        assert_eq!(removed, vec!((98, 1)));
+        assert_eq!(diff(&current, &current, |level| level.0), (vec!(), vec!()));
+    }
+
+    #[test]
+    fn sends_a_snapshot_then_deltas() {
+        let books = BookStore::new();
+        let mut throttled = throttled(ThrottleMode::Delta, Some(2));
+        set_book(&books, &[(100, 5), (99, 3), (98, 1)], &[(200, 5), (201, 3)]);
+
+        let first = throttled.flush(&books);
+        match first.as_slice() {
+            [(Broadcast::OrderbookSnapshot { bids, asks, .. }, 1)] => {
+                assert_eq!(*bids, vec!((100, 5), (99, 3)));
+                assert_eq!(*asks, vec!((200, 5), (201, 3)));
+            },
+            other => panic!("Expected a snapshot: {:?}", other)
+        }
+
+        // Nothing changed, so nothing is sent
+        assert!(throttled.flush(&books).is_empty());
+
+        // The best bid leaves, bringing a level into the top two, and an ask changes volume
+        set_book(&books, &[(99, 3), (98, 1)], &[(200, 7), (201, 3)]);
+        changed(&mut throttled);
+        let second = throttled.flush(&books);
+        match second.as_slice() {
+            [(Broadcast::OrderbookRemove { bids: removed_bids, asks: removed_asks, .. }, 2),
+             (Broadcast::OrderbookUpdate { bids, asks, .. }, 3)] => {
+                assert_eq!(*removed_bids, vec!((100, 5)));
+                assert!(removed_asks.is_empty());
+                assert_eq!(*bids, vec!((98, 1)));
+                assert_eq!(*asks, vec!((200, 7)));
+            },
+            other => panic!("Expected a remove and an update: {:?}", other)
+        }
+
+        // Resending starts again from a snapshot, carrying on the same sequence
+        throttled.reset();
+        match throttled.flush(&books).as_slice() {
+            [(Broadcast::OrderbookSnapshot { .. }, 4)] => (),
+            other => panic!("Expected a snapshot: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn sends_snapshots_in_snapshot_mode() {
+        let books = BookStore::new();
+        let mut throttled = throttled(ThrottleMode::Snapshot, None);
+        set_book(&books, &[(100, 5)], &[(200, 5)]);
+        throttled.flush(&books);
+
+        set_book(&books, &[(100, 6)], &[(200, 5)]);
+        changed(&mut throttled);
+        match throttled.flush(&books).as_slice() {
+            [(Broadcast::OrderbookSnapshot { bids, .. }, 2)] => assert_eq!(*bids, vec!((100, 6))),
+            other => panic!("Expected a snapshot: {:?}", other)
+        }
+    }
+}
\ No newline at end of file
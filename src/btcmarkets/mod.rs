@@ -3,7 +3,8 @@ mod api;
 use self::api::*;
 use broadcast_api::{Broadcast, BroadcastType};
 use super::domain::*;
-use consumer::{self, handler::HandlerCore, MarketHandler, ConnectionFactory};
+use config::ExchangeConfig;
+use consumer::{error::*, handler::{HandlerCore, HandlerContext}, MarketHandler, ConnectionFactory};
 use std::collections::HashMap;
 use ws;
 
@@ -13,6 +14,7 @@ type OrderbookBidsAndAsks = (Vec<OrderbookEntry>, Vec<OrderbookEntry>);
 pub struct BtcmarketsHandler {
     inner: HandlerCore,
     orderbook_snapshots: HashMap<String, OrderbookBidsAndAsks>,
+    symbols: SymbolTable,
     pairs: Vec<CurrencyPair>,
 }
 
@@ -23,12 +25,14 @@ impl ws::Handler for BtcmarketsHandler {
     generic_on_message!(Response);
 
     generic_on_close!(Exchange::BtcMarkets);
+
+    generic_on_timeout!(Exchange::BtcMarkets);
 }
 
 impl MarketHandler for BtcmarketsHandler {
 
-    fn get_requests(pairs: &[CurrencyPair]) -> Vec<String> {
-        pairs.iter().flat_map(|currency_pair| {
+    fn get_requests(&self) -> Vec<String> {
+        self.pairs.iter().flat_map(|currency_pair| {
             let pair = Self::stringify_pair(currency_pair);
             vec!(
                 Request::JoinQueue {
@@ -43,25 +47,29 @@ impl MarketHandler for BtcmarketsHandler {
     }
 
     fn stringify_pair(pair: &CurrencyPair) -> String {
-        match *pair {
-            CurrencyPair::XRPBTC => "XRPBTC"
-        }.to_string()
+        format!("{}{}", pair.base, pair.quote)
     }
 }
 
 pub struct BtcmarketsFactory {
-    broadcast_tx: ws::Sender,
+    context: HandlerContext,
     pairs: Vec<CurrencyPair>,
 }
 
 impl ConnectionFactory for BtcmarketsFactory {
+    const EXCHANGE: Exchange = Exchange::BtcMarkets;
 
-    fn new(broadcast_tx: ws::Sender, pairs: Vec<CurrencyPair>) -> Self {
-        Self { broadcast_tx, pairs }
+    fn new(context: HandlerContext, pairs: Vec<CurrencyPair>, _exchange: &ExchangeConfig) -> Self {
+        Self { context, pairs }
     }
 
-    fn get_connect_addr() -> ::url::Url {
-        ::url::Url::parse(dotenv!("BTCMARKETS_ADDR")).unwrap()
+    fn handler(&self, inner: HandlerCore) -> Self::Handler {
+        Self::Handler {
+            inner,
+            orderbook_snapshots: HashMap::new(),
+            symbols: SymbolTable::new(&self.pairs, BtcmarketsHandler::stringify_pair),
+            pairs: self.pairs.clone(),
+        }
     }
 }
 
@@ -69,27 +77,28 @@ impl ws::Factory for BtcmarketsFactory {
     type Handler = BtcmarketsHandler;
 
     fn connection_made(&mut self, sender: ws::Sender) -> Self::Handler {
-        BtcmarketsHandler {
-            inner: HandlerCore::new(self.broadcast_tx.clone(), sender),
-            orderbook_snapshots: HashMap::new(),
-            pairs: self.pairs.clone(),
-        }
+        self.handler(HandlerCore::new(self.context.clone(), sender, Self::EXCHANGE))
     }
 }
 
 impl BtcmarketsHandler {
-    fn handle_response(&mut self, response: Response) -> BroadcastType {
+    fn handle_response(&mut self, response: Response) -> Result<BroadcastType> {
         match response {
             Response::OrderbookSnapshot { currency, instrument, bids, asks, .. } => {
-                let pair = map_pair_code(&instrument, &currency);
-                map_orderbook_change(&mut self.orderbook_snapshots, pair, bids, asks)
+                let pair = map_pair_code(&self.symbols, &instrument, &currency)?;
+
+                // There is no confirmation of subscriptions, so the first orderbook for the pair stands in for one
+                if !self.orderbook_snapshots.contains_key(&Self::stringify_pair(&pair)) {
+                    self.inner.pair_subscribed(&pair);
+                }
+                Ok(map_orderbook_change(&mut self.orderbook_snapshots, pair, bids, asks))
             },
             Response::Trade { currency, instrument, trades, .. } => {
-                let pair = map_pair_code(&instrument, &currency);
+                let pair = map_pair_code(&self.symbols, &instrument, &currency)?;
                 let broadcast = Broadcast::TradeSnapshot { source: Exchange::BtcMarkets, pair, trades };
-                BroadcastType::One(broadcast)
+                Ok(BroadcastType::One(broadcast))
             }
-            _ => BroadcastType::None
+            _ => Ok(BroadcastType::None)
         }
     }
 }
@@ -105,7 +114,7 @@ fn map_orderbook_change(orderbook_snapshots: &mut HashMap<String, OrderbookBidsA
 
         let broadcast = Broadcast::OrderbookSnapshot {
             source: Exchange::BtcMarkets,
-            pair,
+            pair: pair.clone(),
             bids: bids.into_iter().map(|(price, amount, _)| (price, amount)).collect(),
             asks: asks.into_iter().map(|(price, amount, _)| (price, amount)).collect()
         };
@@ -125,7 +134,7 @@ fn map_orderbook_change(orderbook_snapshots: &mut HashMap<String, OrderbookBidsA
     if !removed_bids.is_empty() || !removed_asks.is_empty() {
         responses.push(Broadcast::OrderbookRemove {
             source: Exchange::BtcMarkets,
-            pair,
+            pair: pair.clone(),
             bids: removed_bids.into_iter().map(|(price, amount, _)| (price, amount)).collect(),
             asks: removed_asks.into_iter().map(|(price, amount, _)| (price, amount)).collect()
         });
@@ -150,9 +159,10 @@ fn diff(first: &Vec<OrderbookEntry>, second: &Vec<OrderbookEntry>) -> (Vec<Order
 }
 
 // Supported pairs list: https://api.btcmarkets.net/v2/market/active
-fn map_pair_code(instrument: &str, currency: &str) -> CurrencyPair {
-    match format!("{}{}", instrument, currency).as_str() {
-        "XRPBTC" => CurrencyPair::XRPBTC,
-        _ => panic!("Could not map pair code {}{}", instrument, currency)
+fn map_pair_code(symbols: &SymbolTable, instrument: &str, currency: &str) -> Result<CurrencyPair> {
+    let pair_code = format!("{}{}", instrument, currency);
+    match symbols.pair(&pair_code) {
+        Some(pair) => Ok(pair.clone()),
+        None => Err(ErrorKind::UnknownPair(Exchange::BtcMarkets, pair_code).into())
     }
 }
\ No newline at end of file
@@ -0,0 +1,29 @@
+error_chain! {
+    errors {
+        ConfigFile(path: String) {
+            description("could not read configuration file")
+            display("could not read configuration file {}", path)
+        }
+        MissingValue(setting: String) {
+            description("missing configuration value")
+            display("no value was given for {}", setting)
+        }
+        InvalidValue(setting: String, value: String, reason: String) {
+            description("invalid configuration value")
+            display("invalid value '{}' for {}: {}", value, setting, reason)
+        }
+        UnsupportedSetting(setting: String) {
+            description("unsupported configuration value")
+            display("{} are not supported", setting)
+        }
+        NoExchangesEnabled {
+            description("no exchanges enabled")
+            display("at least one exchange must be enabled")
+        }
+    }
+
+    foreign_links {
+        Io(::std::io::Error);
+        Toml(::toml::de::Error);
+    }
+}
\ No newline at end of file
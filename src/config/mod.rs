@@ -0,0 +1,745 @@
+mod error;
+
+pub use self::error::*;
+
+use bitfinex::api::{Frequency, Precision};
+use broadcast_api::SlowClientPolicy;
+use clap::{App, Arg, ArgMatches};
+use domain::CurrencyPair;
+use log::LevelFilter;
+use std::{collections::HashMap, env, fs, net::SocketAddr, path::PathBuf, str::FromStr, time::Duration};
+use url::Url;
+
+const DEFAULT_SERVER_ADDR: &str = "127.0.0.1:60400";
+const DEFAULT_COMPRESSION_LEVEL: u32 = 6;
+const DEFAULT_SLOW_CLIENT_MAX_QUEUED: usize = 5000;
+const DEFAULT_SLOW_CLIENT_POLICY: SlowClientPolicy = SlowClientPolicy::Disconnect;
+const DEFAULT_HTTP_ADDR: &str = "127.0.0.1:60401";
+const DEFAULT_LOG_FILE_PATH: &str = "./aggregator.log";
+const DEFAULT_LOG_LEVEL: &str = "debug";
+const DEFAULT_RECONNECT_DELAY_SECS: u64 = 10;
+const DEFAULT_RECONNECT_MAX_DELAY_SECS: u64 = 300;
+const DEFAULT_FAILURE_THRESHOLD: u32 = 5;
+const DEFAULT_STALE_AFTER_SECS: u64 = 30;
+const DEFAULT_RECORDER_DIR: &str = "./captures";
+const DEFAULT_RECORDER_ROTATE_SECS: u64 = 3600;
+const DEFAULT_RECORDER_RETENTION_SECS: u64 = 7 * 24 * 3600;
+const DEFAULT_REPLAY_SPEED: f64 = 1.0;
+
+const DEFAULT_BOOK_SUBSCRIPTION: BookSubscription = BookSubscription {
+    precision: Precision::R0,
+    frequency: Frequency::F0,
+    length: 100
+};
+const BOOK_LENGTHS: &[u32] = &[1, 25, 100, 250];
+
+const DEFAULT_BITFINEX_ADDR: &str = "wss://api.bitfinex.com/ws/2";
+const DEFAULT_BTCMARKETS_ADDR: &str = "ws://localhost:10001";
+const DEFAULT_POLONIEX_ADDR: &str = "wss://api2.poloniex.com";
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub server_addr: SocketAddr,
+    // Clients are only sent compressed messages when they offer permessage-deflate
+    pub compression: Option<CompressionConfig>,
+    pub slow_clients: SlowClientConfig,
+    pub http_addr: SocketAddr,
+    pub log_file_path: String,
+    pub log_level: LevelFilter,
+    pub pairs: Vec<CurrencyPair>,
+    // Exchanges that have been disabled are None
+    pub bitfinex: Option<ExchangeConfig>,
+    pub btcmarkets: Option<ExchangeConfig>,
+    pub poloniex: Option<ExchangeConfig>,
+    pub reconnect: ReconnectConfig,
+    pub recorder: Option<RecorderConfig>,
+    // Exchanges are not connected to when replaying
+    pub replay: Option<ReplayConfig>,
+    // Settings that were accepted but should be changed, logged once the logger is set up
+    pub warnings: Vec<String>
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CompressionConfig {
+    // From 0 for no compression to 9 for the best
+    pub level: u32,
+    // Whether compression carries on from one message to the next rather than starting afresh
+    // Compresses repetitive messages better, but holds on to more memory for each client
+    pub context_takeover: bool
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SlowClientConfig {
+    // Messages sent to a client that it has not yet been seen to receive, after which the policy applies
+    pub max_queued: usize,
+    pub policy: SlowClientPolicy
+}
+
+#[derive(Debug, Clone)]
+pub struct ExchangeConfig {
+    pub addr: Url,
+    // The aggregator is not ready while a required exchange is down
+    pub required: bool,
+    pub books: BookConfig
+}
+
+// How book channels are subscribed to, which only Bitfinex allows to be chosen
+// Pairs without settings of their own use the default
+#[derive(Debug, Clone)]
+pub struct BookConfig {
+    pub default: BookSubscription,
+    pub pairs: HashMap<CurrencyPair, BookSubscription>
+}
+
+impl BookConfig {
+    pub fn subscription(&self, pair: &CurrencyPair) -> BookSubscription {
+        self.pairs.get(pair).cloned().unwrap_or(self.default)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BookSubscription {
+    pub precision: Precision,
+    pub frequency: Frequency,
+    // Number of orders or price levels on each side of the book
+    pub length: u32
+}
+
+#[derive(Debug, Clone)]
+pub struct ReconnectConfig {
+    // Delay before the first attempt to reconnect, which doubles with each consecutive failure
+    pub delay: Duration,
+    // Longest delay between attempts, which is also used while an exchange is down
+    pub max_delay: Duration,
+    // Consecutive failures after which an exchange is considered down
+    pub failure_threshold: u32,
+    // Silence from an exchange after which its feed is considered stale and the connection is dropped
+    pub stale_after: Duration
+}
+
+#[derive(Debug, Clone)]
+pub struct RecorderConfig {
+    pub dir: PathBuf,
+    // How long each capture file is written to before starting the next
+    pub rotate: Duration,
+    // Capture files last written to longer ago than this are deleted
+    pub retention: Duration
+}
+
+#[derive(Debug, Clone)]
+pub struct ReplayConfig {
+    // A capture file, or a directory of them that are replayed in order
+    pub path: PathBuf,
+    // Multiple of the recorded pace, or None to replay as fast as possible
+    pub speed: Option<f64>
+}
+
+impl Config {
+    // Settings are read from the configuration file, then the environment, then the command line
+    // Each source overrides any values given by the sources before it
+    pub fn load() -> Result<Config> {
+        let args = cli().get_matches();
+
+        let mut raw = match args.value_of("config").map(String::from).or_else(|| env_var("CONFIG_FILE")) {
+            Some(path) => RawConfig::from_file(&path)?,
+            None => RawConfig::default()
+        };
+        raw.override_with(RawConfig::from_env()?);
+        raw.override_with(RawConfig::from_args(&args)?);
+
+        raw.validate()
+    }
+}
+
+fn cli<'a, 'b>() -> App<'a, 'b> {
+    App::new("market-aggregator")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("Aggregates cryptocurrency exchange market data into a single WebSocket feed")
+        .arg(Arg::with_name("config").long("config").short("c").takes_value(true)
+            .help("Path to a TOML configuration file"))
+        .arg(Arg::with_name("server-addr").long("server-addr").takes_value(true)
+            .help("Address to bind the broadcast server to"))
+        .arg(Arg::with_name("compress").long("compress")
+            .help("Compress messages to clients that offer permessage-deflate"))
+        .arg(Arg::with_name("compression-level").long("compression-level").takes_value(true)
+            .help("Compression level from 0 to 9"))
+        .arg(Arg::with_name("no-context-takeover").long("no-context-takeover")
+            .help("Compress each message to clients on its own, which uses less memory but compresses less"))
+        .arg(Arg::with_name("slow-client-max-queued").long("slow-client-max-queued").takes_value(true)
+            .help("Messages a client may fall behind by before it is treated as slow"))
+        .arg(Arg::with_name("slow-client-policy").long("slow-client-policy").takes_value(true)
+            .help("What to do with slow clients: disconnect, resync or conflate"))
+        .arg(Arg::with_name("http-addr").long("http-addr").takes_value(true)
+            .help("Address to bind the HTTP monitoring server to"))
+        .arg(Arg::with_name("log-file").long("log-file").takes_value(true)
+            .help("Path of the log file"))
+        .arg(Arg::with_name("log-level").long("log-level").takes_value(true)
+            .help("One of off, error, warn, info, debug or trace"))
+        .arg(Arg::with_name("pairs").long("pairs").takes_value(true)
+            .help("Comma separated currency pairs, for example XRP/BTC,ETH/BTC"))
+        .arg(Arg::with_name("bitfinex-addr").long("bitfinex-addr").takes_value(true)
+            .help("WebSocket address of the Bitfinex API"))
+        .arg(Arg::with_name("bitfinex-book-precision").long("bitfinex-book-precision").takes_value(true)
+            .help("Bitfinex book precision: R0 for individual orders or P0 to P3 for price levels"))
+        .arg(Arg::with_name("bitfinex-book-frequency").long("bitfinex-book-frequency").takes_value(true)
+            .help("Bitfinex book frequency: F0 for real time or F1 for every two seconds"))
+        .arg(Arg::with_name("bitfinex-book-length").long("bitfinex-book-length").takes_value(true)
+            .help("Number of orders or price levels in Bitfinex books: 1, 25, 100 or 250"))
+        .arg(Arg::with_name("btcmarkets-addr").long("btcmarkets-addr").takes_value(true)
+            .help("WebSocket address of the BTCMarkets API"))
+        .arg(Arg::with_name("poloniex-addr").long("poloniex-addr").takes_value(true)
+            .help("WebSocket address of the Poloniex API"))
+        .arg(Arg::with_name("enable").long("enable").takes_value(true)
+            .help("Comma separated exchanges to enable"))
+        .arg(Arg::with_name("disable").long("disable").takes_value(true)
+            .help("Comma separated exchanges to disable"))
+        .arg(Arg::with_name("require").long("require").takes_value(true)
+            .help("Comma separated exchanges that must be up for the aggregator to be ready"))
+        .arg(Arg::with_name("optional").long("optional").takes_value(true)
+            .help("Comma separated exchanges that may be down while the aggregator is ready"))
+        .arg(Arg::with_name("reconnect-delay").long("reconnect-delay").takes_value(true)
+            .help("Seconds to wait before first reconnecting to an exchange"))
+        .arg(Arg::with_name("reconnect-max-delay").long("reconnect-max-delay").takes_value(true)
+            .help("Most seconds to wait between attempts to reconnect to an exchange, and the wait while it is down"))
+        .arg(Arg::with_name("failure-threshold").long("failure-threshold").takes_value(true)
+            .help("Consecutive connection failures after which an exchange is considered down"))
+        .arg(Arg::with_name("stale-after").long("stale-after").takes_value(true)
+            .help("Seconds without any message from an exchange before reconnecting to it"))
+        .arg(Arg::with_name("subscribe-retry").long("subscribe-retry").takes_value(true).hidden(true)
+            .help("Deprecated and ignored, failed subscriptions are retried by reconnecting"))
+        .arg(Arg::with_name("record").long("record")
+            .help("Record every message received from the exchanges"))
+        .arg(Arg::with_name("record-dir").long("record-dir").takes_value(true)
+            .help("Directory to write recorded exchange messages to"))
+        .arg(Arg::with_name("record-rotate").long("record-rotate").takes_value(true)
+            .help("Seconds to write each recording file for before starting another"))
+        .arg(Arg::with_name("record-retention").long("record-retention").takes_value(true)
+            .help("Seconds to keep recording files for"))
+        .arg(Arg::with_name("replay").long("replay").takes_value(true)
+            .help("Replay recorded exchange messages from a capture file or directory instead of connecting to the exchanges"))
+        .arg(Arg::with_name("replay-speed").long("replay-speed").takes_value(true)
+            .help("Multiple of the recorded pace to replay at, or 0 for as fast as possible"))
+}
+
+// Every value is optional here so that sources can be layered on top of each other
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawConfig {
+    #[serde(default)]
+    server: RawServerConfig,
+    #[serde(default)]
+    http: RawHttpConfig,
+    #[serde(default)]
+    log: RawLogConfig,
+    pairs: Option<Vec<String>>,
+    #[serde(default)]
+    exchanges: RawExchangesConfig,
+    #[serde(default)]
+    reconnect: RawReconnectConfig,
+    #[serde(default)]
+    recorder: RawRecorderConfig,
+    #[serde(default)]
+    replay: RawReplayConfig
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawServerConfig {
+    addr: Option<String>,
+    #[serde(default)]
+    compression: RawCompressionConfig,
+    #[serde(default)]
+    slow_clients: RawSlowClientConfig
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawCompressionConfig {
+    enabled: Option<bool>,
+    level: Option<u32>,
+    context_takeover: Option<bool>
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawSlowClientConfig {
+    max_queued: Option<usize>,
+    policy: Option<String>
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawHttpConfig {
+    addr: Option<String>
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawLogConfig {
+    file_path: Option<String>,
+    level: Option<String>
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawExchangesConfig {
+    #[serde(default)]
+    bitfinex: RawExchangeConfig,
+    #[serde(default)]
+    btcmarkets: RawExchangeConfig,
+    #[serde(default)]
+    poloniex: RawExchangeConfig
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawExchangeConfig {
+    enabled: Option<bool>,
+    required: Option<bool>,
+    addr: Option<String>,
+    #[serde(default)]
+    book: RawBookConfig,
+    // Settings for individual pairs, keyed by pair
+    #[serde(default)]
+    books: HashMap<String, RawBookConfig>
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawBookConfig {
+    precision: Option<String>,
+    frequency: Option<String>,
+    length: Option<u32>
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawReconnectConfig {
+    delay_secs: Option<u64>,
+    max_delay_secs: Option<u64>,
+    failure_threshold: Option<u32>,
+    stale_after_secs: Option<u64>,
+    // Deprecated and ignored, since failed subscriptions now close the connection to be retried by reconnecting
+    subscribe_retry_secs: Option<u64>
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawRecorderConfig {
+    enabled: Option<bool>,
+    dir: Option<String>,
+    rotate_secs: Option<u64>,
+    retention_secs: Option<u64>
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawReplayConfig {
+    path: Option<String>,
+    speed: Option<f64>
+}
+
+impl RawConfig {
+    fn from_file(path: &str) -> Result<RawConfig> {
+        let contents = fs::read_to_string(path).chain_err(|| ErrorKind::ConfigFile(path.to_string()))?;
+        let raw = ::toml::from_str(&contents).chain_err(|| ErrorKind::ConfigFile(path.to_string()))?;
+        Ok(raw)
+    }
+
+    fn from_env() -> Result<RawConfig> {
+        let mut raw = RawConfig::default();
+
+        raw.server.addr = env_var("SERVER_ADDR");
+        raw.server.compression.enabled = parse_optional("COMPRESSION_ENABLED", env_var("COMPRESSION_ENABLED"))?;
+        raw.server.compression.level = parse_optional("COMPRESSION_LEVEL", env_var("COMPRESSION_LEVEL"))?;
+        raw.server.compression.context_takeover =
+            parse_optional("COMPRESSION_CONTEXT_TAKEOVER", env_var("COMPRESSION_CONTEXT_TAKEOVER"))?;
+        raw.server.slow_clients.max_queued = parse_optional("SLOW_CLIENT_MAX_QUEUED", env_var("SLOW_CLIENT_MAX_QUEUED"))?;
+        raw.server.slow_clients.policy = env_var("SLOW_CLIENT_POLICY");
+        raw.http.addr = env_var("HTTP_ADDR");
+        raw.log.file_path = env_var("LOG_FILE_PATH");
+        raw.log.level = env_var("LOG_LEVEL");
+        raw.pairs = env_var("CURRENCY_PAIRS").map(|pairs| split_list(&pairs));
+
+        raw.exchanges.bitfinex.addr = env_var("BITFINEX_ADDR");
+        raw.exchanges.bitfinex.enabled = parse_optional("BITFINEX_ENABLED", env_var("BITFINEX_ENABLED"))?;
+        raw.exchanges.bitfinex.required = parse_optional("BITFINEX_REQUIRED", env_var("BITFINEX_REQUIRED"))?;
+        raw.exchanges.bitfinex.book.precision = env_var("BITFINEX_BOOK_PRECISION");
+        raw.exchanges.bitfinex.book.frequency = env_var("BITFINEX_BOOK_FREQUENCY");
+        raw.exchanges.bitfinex.book.length = parse_optional("BITFINEX_BOOK_LENGTH", env_var("BITFINEX_BOOK_LENGTH"))?;
+        raw.exchanges.btcmarkets.addr = env_var("BTCMARKETS_ADDR");
+        raw.exchanges.btcmarkets.enabled = parse_optional("BTCMARKETS_ENABLED", env_var("BTCMARKETS_ENABLED"))?;
+        raw.exchanges.btcmarkets.required = parse_optional("BTCMARKETS_REQUIRED", env_var("BTCMARKETS_REQUIRED"))?;
+        raw.exchanges.poloniex.addr = env_var("POLONIEX_ADDR");
+        raw.exchanges.poloniex.enabled = parse_optional("POLONIEX_ENABLED", env_var("POLONIEX_ENABLED"))?;
+        raw.exchanges.poloniex.required = parse_optional("POLONIEX_REQUIRED", env_var("POLONIEX_REQUIRED"))?;
+
+        raw.reconnect.delay_secs = parse_optional("RECONNECT_DELAY_SECS", env_var("RECONNECT_DELAY_SECS"))?;
+        raw.reconnect.max_delay_secs = parse_optional("RECONNECT_MAX_DELAY_SECS", env_var("RECONNECT_MAX_DELAY_SECS"))?;
+        raw.reconnect.failure_threshold = parse_optional("FAILURE_THRESHOLD", env_var("FAILURE_THRESHOLD"))?;
+        raw.reconnect.stale_after_secs = parse_optional("STALE_AFTER_SECS", env_var("STALE_AFTER_SECS"))?;
+        raw.reconnect.subscribe_retry_secs = parse_optional("SUBSCRIBE_RETRY_SECS", env_var("SUBSCRIBE_RETRY_SECS"))?;
+
+        raw.recorder.enabled = parse_optional("RECORDER_ENABLED", env_var("RECORDER_ENABLED"))?;
+        raw.recorder.dir = env_var("RECORDER_DIR");
+        raw.recorder.rotate_secs = parse_optional("RECORDER_ROTATE_SECS", env_var("RECORDER_ROTATE_SECS"))?;
+        raw.recorder.retention_secs = parse_optional("RECORDER_RETENTION_SECS", env_var("RECORDER_RETENTION_SECS"))?;
+
+        raw.replay.path = env_var("REPLAY_PATH");
+        raw.replay.speed = parse_optional("REPLAY_SPEED", env_var("REPLAY_SPEED"))?;
+
+        Ok(raw)
+    }
+
+    fn from_args(args: &ArgMatches) -> Result<RawConfig> {
+        let arg = |name: &str| args.value_of(name).map(String::from);
+
+        let mut raw = RawConfig::default();
+
+        raw.server.addr = arg("server-addr");
+        if args.is_present("compress") {
+            raw.server.compression.enabled = Some(true);
+        }
+        raw.server.compression.level = parse_optional("--compression-level", arg("compression-level"))?;
+        if args.is_present("no-context-takeover") {
+            raw.server.compression.context_takeover = Some(false);
+        }
+        raw.server.slow_clients.max_queued = parse_optional("--slow-client-max-queued", arg("slow-client-max-queued"))?;
+        raw.server.slow_clients.policy = arg("slow-client-policy");
+        raw.http.addr = arg("http-addr");
+        raw.log.file_path = arg("log-file");
+        raw.log.level = arg("log-level");
+        raw.pairs = arg("pairs").map(|pairs| split_list(&pairs));
+
+        raw.exchanges.bitfinex.addr = arg("bitfinex-addr");
+        raw.exchanges.bitfinex.book.precision = arg("bitfinex-book-precision");
+        raw.exchanges.bitfinex.book.frequency = arg("bitfinex-book-frequency");
+        raw.exchanges.bitfinex.book.length = parse_optional("--bitfinex-book-length", arg("bitfinex-book-length"))?;
+        raw.exchanges.btcmarkets.addr = arg("btcmarkets-addr");
+        raw.exchanges.poloniex.addr = arg("poloniex-addr");
+
+        for (flag, enabled) in [("enable", true), ("disable", false)] {
+            for name in arg(flag).map(|names| split_list(&names)).unwrap_or_default() {
+                match raw.exchanges.get_mut(&name) {
+                    Some(exchange) => exchange.enabled = Some(enabled),
+                    None => bail!(ErrorKind::InvalidValue(format!("--{}", flag), name,
+                        "expected one of bitfinex, btcmarkets or poloniex".to_string()))
+                }
+            }
+        }
+
+        for (flag, required) in [("require", true), ("optional", false)] {
+            for name in arg(flag).map(|names| split_list(&names)).unwrap_or_default() {
+                match raw.exchanges.get_mut(&name) {
+                    Some(exchange) => exchange.required = Some(required),
+                    None => bail!(ErrorKind::InvalidValue(format!("--{}", flag), name,
+                        "expected one of bitfinex, btcmarkets or poloniex".to_string()))
+                }
+            }
+        }
+
+        raw.reconnect.delay_secs = parse_optional("--reconnect-delay", arg("reconnect-delay"))?;
+        raw.reconnect.max_delay_secs = parse_optional("--reconnect-max-delay", arg("reconnect-max-delay"))?;
+        raw.reconnect.failure_threshold = parse_optional("--failure-threshold", arg("failure-threshold"))?;
+        raw.reconnect.stale_after_secs = parse_optional("--stale-after", arg("stale-after"))?;
+        raw.reconnect.subscribe_retry_secs = parse_optional("--subscribe-retry", arg("subscribe-retry"))?;
+
+        if args.is_present("record") {
+            raw.recorder.enabled = Some(true);
+        }
+        raw.recorder.dir = arg("record-dir");
+        raw.recorder.rotate_secs = parse_optional("--record-rotate", arg("record-rotate"))?;
+        raw.recorder.retention_secs = parse_optional("--record-retention", arg("record-retention"))?;
+
+        raw.replay.path = arg("replay");
+        raw.replay.speed = parse_optional("--replay-speed", arg("replay-speed"))?;
+
+        Ok(raw)
+    }
+
+    fn override_with(&mut self, other: RawConfig) {
+        override_value(&mut self.server.addr, other.server.addr);
+        override_value(&mut self.server.compression.enabled, other.server.compression.enabled);
+        override_value(&mut self.server.compression.level, other.server.compression.level);
+        override_value(&mut self.server.compression.context_takeover, other.server.compression.context_takeover);
+        override_value(&mut self.server.slow_clients.max_queued, other.server.slow_clients.max_queued);
+        override_value(&mut self.server.slow_clients.policy, other.server.slow_clients.policy);
+        override_value(&mut self.http.addr, other.http.addr);
+        override_value(&mut self.log.file_path, other.log.file_path);
+        override_value(&mut self.log.level, other.log.level);
+        override_value(&mut self.pairs, other.pairs);
+        self.exchanges.bitfinex.override_with(other.exchanges.bitfinex);
+        self.exchanges.btcmarkets.override_with(other.exchanges.btcmarkets);
+        self.exchanges.poloniex.override_with(other.exchanges.poloniex);
+        override_value(&mut self.reconnect.delay_secs, other.reconnect.delay_secs);
+        override_value(&mut self.reconnect.max_delay_secs, other.reconnect.max_delay_secs);
+        override_value(&mut self.reconnect.failure_threshold, other.reconnect.failure_threshold);
+        override_value(&mut self.reconnect.stale_after_secs, other.reconnect.stale_after_secs);
+        override_value(&mut self.reconnect.subscribe_retry_secs, other.reconnect.subscribe_retry_secs);
+        override_value(&mut self.recorder.enabled, other.recorder.enabled);
+        override_value(&mut self.recorder.dir, other.recorder.dir);
+        override_value(&mut self.recorder.rotate_secs, other.recorder.rotate_secs);
+        override_value(&mut self.recorder.retention_secs, other.recorder.retention_secs);
+        override_value(&mut self.replay.path, other.replay.path);
+        override_value(&mut self.replay.speed, other.replay.speed);
+    }
+
+    fn validate(self) -> Result<Config> {
+        let server_addr = self.server.addr.unwrap_or_else(|| DEFAULT_SERVER_ADDR.to_string());
+        let server_addr = SocketAddr::from_str(&server_addr).map_err(|e|
+            ErrorKind::InvalidValue("server address".to_string(), server_addr.clone(), e.to_string()))?;
+
+        let compression = if self.server.compression.enabled.unwrap_or(false) {
+            let level = self.server.compression.level.unwrap_or(DEFAULT_COMPRESSION_LEVEL);
+            if level > 9 {
+                bail!(ErrorKind::InvalidValue("compression level".to_string(), level.to_string(),
+                    "must be from 0 to 9".to_string()));
+            }
+
+            Some(CompressionConfig {
+                level,
+                context_takeover: self.server.compression.context_takeover.unwrap_or(true)
+            })
+        } else {
+            None
+        };
+
+        let max_queued = self.server.slow_clients.max_queued.unwrap_or(DEFAULT_SLOW_CLIENT_MAX_QUEUED);
+        if max_queued == 0 {
+            bail!(ErrorKind::InvalidValue("slow client queue limit".to_string(), max_queued.to_string(),
+                "must be at least one message".to_string()));
+        }
+        let slow_clients = SlowClientConfig {
+            max_queued,
+            policy: parse_optional("slow client policy", self.server.slow_clients.policy)?.unwrap_or(DEFAULT_SLOW_CLIENT_POLICY)
+        };
+
+        let http_addr = self.http.addr.unwrap_or_else(|| DEFAULT_HTTP_ADDR.to_string());
+        let http_addr = SocketAddr::from_str(&http_addr).map_err(|e|
+            ErrorKind::InvalidValue("HTTP address".to_string(), http_addr.clone(), e.to_string()))?;
+
+        let log_level = self.log.level.unwrap_or_else(|| DEFAULT_LOG_LEVEL.to_string());
+        let log_level = LevelFilter::from_str(&log_level).map_err(|_|
+            ErrorKind::InvalidValue("log level".to_string(), log_level.clone(),
+                "expected one of off, error, warn, info, debug or trace".to_string()))?;
+
+        let mut pairs = vec!();
+        for pair in self.pairs.unwrap_or_default() {
+            match CurrencyPair::map(&pair) {
+                Some(parsed) => pairs.push(parsed),
+                None => bail!(ErrorKind::InvalidValue("currency pair".to_string(), pair,
+                    "expected BASE/QUOTE, for example XRP/BTC".to_string()))
+            }
+        }
+        if pairs.is_empty() {
+            bail!(ErrorKind::MissingValue("currency pairs".to_string()));
+        }
+        pairs.sort_unstable();
+        pairs.dedup();
+
+        let bitfinex = self.exchanges.bitfinex.validate("bitfinex", Some(DEFAULT_BITFINEX_ADDR), &pairs, true)?;
+        let btcmarkets = self.exchanges.btcmarkets.validate("btcmarkets", Some(DEFAULT_BTCMARKETS_ADDR), &pairs, false)?;
+        let poloniex = self.exchanges.poloniex.validate("poloniex", Some(DEFAULT_POLONIEX_ADDR), &pairs, false)?;
+        if bitfinex.is_none() && btcmarkets.is_none() && poloniex.is_none() {
+            bail!(ErrorKind::NoExchangesEnabled);
+        }
+
+        let stale_after_secs = self.reconnect.stale_after_secs.unwrap_or(DEFAULT_STALE_AFTER_SECS);
+        if stale_after_secs == 0 {
+            bail!(ErrorKind::InvalidValue("stale feed timeout".to_string(), stale_after_secs.to_string(),
+                "must be at least one second".to_string()));
+        }
+
+        let delay_secs = self.reconnect.delay_secs.unwrap_or(DEFAULT_RECONNECT_DELAY_SECS);
+        let max_delay_secs = self.reconnect.max_delay_secs.unwrap_or(DEFAULT_RECONNECT_MAX_DELAY_SECS);
+        if max_delay_secs < delay_secs {
+            bail!(ErrorKind::InvalidValue("maximum reconnect delay".to_string(), max_delay_secs.to_string(),
+                format!("must be at least the reconnect delay of {}", delay_secs)));
+        }
+
+        let failure_threshold = self.reconnect.failure_threshold.unwrap_or(DEFAULT_FAILURE_THRESHOLD);
+        if failure_threshold == 0 {
+            bail!(ErrorKind::InvalidValue("failure threshold".to_string(), failure_threshold.to_string(),
+                "must be at least one failure".to_string()));
+        }
+
+        let reconnect = ReconnectConfig {
+            delay: Duration::from_secs(delay_secs),
+            max_delay: Duration::from_secs(max_delay_secs),
+            failure_threshold,
+            stale_after: Duration::from_secs(stale_after_secs)
+        };
+
+        let mut warnings = vec!();
+        if self.reconnect.subscribe_retry_secs.is_some() {
+            warnings.push("The subscribe retry setting is deprecated and ignored, \
+                failed subscriptions close the connection and are retried after the reconnect delay".to_string());
+        }
+
+        let recorder = if self.recorder.enabled.unwrap_or(false) {
+            let rotate_secs = self.recorder.rotate_secs.unwrap_or(DEFAULT_RECORDER_ROTATE_SECS);
+            if rotate_secs == 0 {
+                bail!(ErrorKind::InvalidValue("recording rotation".to_string(), rotate_secs.to_string(),
+                    "must be at least one second".to_string()));
+            }
+
+            let retention_secs = self.recorder.retention_secs.unwrap_or(DEFAULT_RECORDER_RETENTION_SECS);
+            if retention_secs < rotate_secs {
+                bail!(ErrorKind::InvalidValue("recording retention".to_string(), retention_secs.to_string(),
+                    format!("must be at least the recording rotation of {}", rotate_secs)));
+            }
+
+            Some(RecorderConfig {
+                dir: PathBuf::from(self.recorder.dir.unwrap_or_else(|| DEFAULT_RECORDER_DIR.to_string())),
+                rotate: Duration::from_secs(rotate_secs),
+                retention: Duration::from_secs(retention_secs)
+            })
+        } else {
+            None
+        };
+
+        let replay = match self.replay.path {
+            Some(path) => {
+                let speed = self.replay.speed.unwrap_or(DEFAULT_REPLAY_SPEED);
+                if !speed.is_finite() || speed < 0.0 {
+                    bail!(ErrorKind::InvalidValue("replay speed".to_string(), speed.to_string(),
+                        "must be a positive multiple of the recorded pace, or 0 for as fast as possible".to_string()));
+                }
+
+                Some(ReplayConfig { path: PathBuf::from(path), speed: if speed == 0.0 { None } else { Some(speed) } })
+            },
+            None => None
+        };
+
+        Ok(Config {
+            server_addr,
+            compression,
+            slow_clients,
+            http_addr,
+            log_file_path: self.log.file_path.unwrap_or_else(|| DEFAULT_LOG_FILE_PATH.to_string()),
+            log_level,
+            pairs,
+            bitfinex,
+            btcmarkets,
+            poloniex,
+            reconnect,
+            recorder,
+            replay,
+            warnings
+        })
+    }
+}
+
+impl RawExchangesConfig {
+    fn get_mut(&mut self, name: &str) -> Option<&mut RawExchangeConfig> {
+        match name.to_lowercase().as_str() {
+            "bitfinex" => Some(&mut self.bitfinex),
+            "btcmarkets" => Some(&mut self.btcmarkets),
+            "poloniex" => Some(&mut self.poloniex),
+            _ => None
+        }
+    }
+}
+
+impl RawExchangeConfig {
+    fn override_with(&mut self, other: RawExchangeConfig) {
+        override_value(&mut self.enabled, other.enabled);
+        override_value(&mut self.required, other.required);
+        override_value(&mut self.addr, other.addr);
+        self.book.override_with(other.book);
+        self.books.extend(other.books);
+    }
+
+    fn validate(self, name: &str, default_addr: Option<&str>, pairs: &[CurrencyPair], configurable_books: bool)
+        -> Result<Option<ExchangeConfig>> {
+        if !self.enabled.unwrap_or(true) {
+            return Ok(None);
+        }
+
+        if !configurable_books && (!self.book.is_empty() || !self.books.is_empty()) {
+            bail!(ErrorKind::UnsupportedSetting(format!("{} book settings", name)));
+        }
+
+        let setting = format!("{} address", name);
+        let addr = match self.addr.or_else(|| default_addr.map(String::from)) {
+            Some(addr) => addr,
+            None => bail!(ErrorKind::MissingValue(setting))
+        };
+        let url = Url::parse(&addr).map_err(|e|
+            ErrorKind::InvalidValue(setting.clone(), addr.clone(), e.to_string()))?;
+        if url.scheme() != "ws" && url.scheme() != "wss" {
+            bail!(ErrorKind::InvalidValue(setting, addr, "expected a ws:// or wss:// address".to_string()));
+        }
+
+        let default = self.book.validate(&format!("{} book", name), DEFAULT_BOOK_SUBSCRIPTION)?;
+        let mut books = BookConfig { default, pairs: HashMap::new() };
+        for (pair, book) in self.books {
+            let setting = format!("{} book pair", name);
+            let parsed = match CurrencyPair::map(&pair) {
+                Some(parsed) => parsed,
+                None => bail!(ErrorKind::InvalidValue(setting, pair, "expected BASE/QUOTE, for example XRP/BTC".to_string()))
+            };
+            if !pairs.contains(&parsed) {
+                bail!(ErrorKind::InvalidValue(setting, pair, "not one of the configured currency pairs".to_string()));
+            }
+            let subscription = book.validate(&format!("{} {} book", name, parsed), default)?;
+            books.pairs.insert(parsed, subscription);
+        }
+
+        Ok(Some(ExchangeConfig { addr: url, required: self.required.unwrap_or(true), books }))
+    }
+}
+
+impl RawBookConfig {
+    fn is_empty(&self) -> bool {
+        self.precision.is_none() && self.frequency.is_none() && self.length.is_none()
+    }
+
+    fn override_with(&mut self, other: RawBookConfig) {
+        override_value(&mut self.precision, other.precision);
+        override_value(&mut self.frequency, other.frequency);
+        override_value(&mut self.length, other.length);
+    }
+
+    // Settings that are not given are taken from the defaults
+    fn validate(self, setting: &str, defaults: BookSubscription) -> Result<BookSubscription> {
+        let precision = parse_optional(&format!("{} precision", setting), self.precision)?;
+        let frequency = parse_optional(&format!("{} frequency", setting), self.frequency)?;
+        let length = self.length.unwrap_or(defaults.length);
+        if !BOOK_LENGTHS.contains(&length) {
+            bail!(ErrorKind::InvalidValue(format!("{} length", setting), length.to_string(),
+                "expected one of 1, 25, 100 or 250".to_string()));
+        }
+
+        Ok(BookSubscription {
+            precision: precision.unwrap_or(defaults.precision),
+            frequency: frequency.unwrap_or(defaults.frequency),
+            length
+        })
+    }
+}
+
+fn override_value<T>(value: &mut Option<T>, other: Option<T>) {
+    if other.is_some() {
+        *value = other;
+    }
+}
+
+fn env_var(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+fn split_list(values: &str) -> Vec<String> {
+    values.split(',').map(|value| value.trim().to_string()).filter(|value| !value.is_empty()).collect()
+}
+
+fn parse_optional<T: FromStr>(setting: &str, value: Option<String>) -> Result<Option<T>>
+    where T::Err: ::std::fmt::Display {
+    match value {
+        Some(value) => match value.parse::<T>() {
+            Ok(parsed) => Ok(Some(parsed)),
+            Err(e) => bail!(ErrorKind::InvalidValue(setting.to_string(), value.clone(), e.to_string()))
+        },
+        None => Ok(None)
+    }
+}
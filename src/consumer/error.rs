@@ -8,10 +8,29 @@ error_chain! {
             description("could not send multiple broadcasts")
             display("could not send multiple broadcasts: {:?}", broadcast_errors)
         }
+        UnknownPair(exchange: ::domain::Exchange, code: String) {
+            description("unknown pair code")
+            display("{} pair code {} does not match any configured pair", exchange, code)
+        }
+        UnknownChannel(exchange: ::domain::Exchange, channel_id: i64) {
+            description("unknown channel")
+            display("{} sent a message on unknown channel ID {}", exchange, channel_id)
+        }
+        ProtocolViolation(exchange: ::domain::Exchange, reason: String) {
+            description("exchange protocol violation")
+            display("{} sent a message that breaks its protocol: {}", exchange, reason)
+        }
     }
 
+    // ws errors are boxed so that results carrying them stay small
     foreign_links {
-        Ws(::ws::Error);
+        Ws(Box<::ws::Error>);
         Serde(::serde_json::Error);
     }
+}
+
+impl From<::ws::Error> for Error {
+    fn from(e: ::ws::Error) -> Self {
+        ErrorKind::Ws(Box::new(e)).into()
+    }
 }
\ No newline at end of file
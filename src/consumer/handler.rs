@@ -1,31 +1,200 @@
 use ws;
-use broadcast_api::BroadcastType;
+use broadcast_api::{Broadcast, BroadcastType, ConnectionState, Stamped, Timestamp};
+use std::sync::{mpsc, atomic::{AtomicUsize, Ordering}};
+use std::time::Instant;
+use config::ReconnectConfig;
+use domain::{CurrencyPair, Exchange, StatusStore, SubscriptionStore};
+use metrics::Metrics;
+use recorder::Recorder;
+use super::Clock;
 use super::error::*;
 
-pub struct HandlerCore {
+// Timeout used to check whether the exchange has gone silent
+const WATCHDOG: ws::util::Token = ws::util::Token(1);
+
+// Numbers every connection made to any exchange, so that recorded frames can be told apart by connection
+static CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+
+// State shared by every connection to every exchange
+#[derive(Clone)]
+pub struct HandlerContext {
     // Sender to broadcast to consumers connected to this program
-    broadcast_tx: ws::Sender,
-    // Sender to message inbound streams from the exchange
-    exchange_tx: ws::Sender
+    pub broadcast_tx: mpsc::Sender<Stamped>,
+    // Connection state of every exchange and the pairs it has confirmed subscriptions for
+    pub status: StatusStore,
+    pub subscriptions: SubscriptionStore,
+    pub metrics: Metrics,
+    pub recorder: Recorder,
+    pub reconnect: ReconnectConfig,
+    pub clock: Clock
+}
+
+#[cfg(test)]
+impl HandlerContext {
+    // Context for a handler under test, along with the receiving end of whatever it broadcasts
+    pub fn detached() -> (Self, mpsc::Receiver<Stamped>) {
+        let (broadcast_tx, broadcast_rx) = mpsc::channel();
+        let context = Self {
+            broadcast_tx,
+            status: StatusStore::new(),
+            subscriptions: SubscriptionStore::new(),
+            metrics: Metrics::new(),
+            recorder: Recorder::default(),
+            reconnect: ReconnectConfig {
+                delay: ::std::time::Duration::from_secs(1),
+                max_delay: ::std::time::Duration::from_secs(60),
+                failure_threshold: 5,
+                stale_after: ::std::time::Duration::from_secs(30)
+            },
+            clock: Clock::replayed()
+        };
+        (context, broadcast_rx)
+    }
+}
+
+pub struct HandlerCore {
+    context: HandlerContext,
+    exchange: Exchange,
+    connection: u64,
+    // Sender to message inbound streams from the exchange, missing when frames are replayed from a capture
+    exchange_tx: Option<ws::Sender>,
+    // When anything, including a heartbeat, was last received from the exchange
+    last_message: Instant,
+    // Messages from the exchange that could not be handled on this connection
+    errors: u64
 }
 
 impl HandlerCore {
 
-    pub fn new(broadcast_tx: ws::Sender, exchange_tx: ws::Sender) -> Self {
-        Self { broadcast_tx, exchange_tx }
+    pub fn new(context: HandlerContext, exchange_tx: ws::Sender, exchange: Exchange) -> Self {
+        Self::with_sender(context, Some(exchange_tx), exchange)
+    }
+
+    // Requests that would go to the exchange are dropped, since there is no connection to send them on
+    pub fn detached(context: HandlerContext, exchange: Exchange) -> Self {
+        Self::with_sender(context, None, exchange)
+    }
+
+    fn with_sender(context: HandlerContext, exchange_tx: Option<ws::Sender>, exchange: Exchange) -> Self {
+        let connection = CONNECTIONS.fetch_add(1, Ordering::SeqCst) as u64;
+        Self { context, exchange, connection, exchange_tx, last_message: Instant::now(), errors: 0 }
+    }
+
+    // The ws results below are returned from the ws::Handler callbacks of each exchange
+    #[allow(clippy::result_large_err)]
+    pub fn start_watchdog(&mut self) -> ws::Result<()> {
+        self.last_message = Instant::now();
+        match self.exchange_tx {
+            Some(ref exchange_tx) => exchange_tx.timeout(::consumer::millis(self.context.reconnect.stale_after), WATCHDOG),
+            None => Ok(())
+        }
+    }
+
+    pub fn message_received(&mut self) {
+        self.last_message = Instant::now();
+        self.context.metrics.message_received(self.exchange);
+    }
+
+    pub fn capture(&self, frame: &str) {
+        self.context.recorder.record(self.exchange, self.connection, frame);
+    }
+
+    // Drops the connection if the exchange has been silent for too long, otherwise checks again when it could next be stale
+    #[allow(clippy::result_large_err)]
+    pub fn on_timeout(&mut self, exchange: Exchange, event: ws::util::Token) -> ws::Result<()> {
+        let exchange_tx = match self.exchange_tx {
+            Some(ref exchange_tx) if event == WATCHDOG => exchange_tx.clone(),
+            _ => return Ok(())
+        };
+
+        let silence = self.last_message.elapsed();
+        let stale_after = self.context.reconnect.stale_after;
+        if silence < stale_after {
+            return exchange_tx.timeout(::consumer::millis(stale_after - silence), WATCHDOG);
+        }
+
+        warn!("Nothing received from {} for {} seconds, dropping the connection", exchange, silence.as_secs());
+
+        let ts = self.timestamp();
+        let stale = vec!(
+            Broadcast::ExchangeFeedStale { exchange, silence: ::consumer::millis(silence) as i64, ts },
+            Broadcast::ExchangeConnectionClosed { exchange, ts }
+        );
+        if let Err(e) = self.broadcast(BroadcastType::Many(stale)) {
+            warn!("Could not broadcast {} stale feed message: {}", exchange, e);
+        }
+
+        // A silent exchange may never complete a closing handshake, so the connection is shut down instead
+        // Handlers are not told of the close in that case, which is why the closed message is sent above
+        exchange_tx.shutdown()
+    }
+
+    // When the message being handled was received, which is in the past when replaying
+    pub fn timestamp(&self) -> Timestamp {
+        self.context.clock.now()
+    }
+
+    // Marks the exchange as open, which resets its count of connection failures
+    pub fn connection_opened(&mut self, exchange: Exchange) -> Result<()> {
+        self.context.status.set(exchange, ConnectionState::Open, 0);
+        self.context.subscriptions.clear(exchange);
+
+        let ts = self.timestamp();
+        self.broadcast(BroadcastType::Many(vec!(
+            Broadcast::ExchangeConnectionOpened { exchange, ts },
+            Broadcast::ExchangeStatus { exchange, state: ConnectionState::Open, failures: 0, retry: None, ts }
+        )))
+    }
+
+    // Every subscription for the pair has been confirmed by the exchange
+    pub fn pair_subscribed(&self, pair: &CurrencyPair) {
+        debug!("{} subscriptions for {} are confirmed", self.exchange, pair);
+        self.context.subscriptions.add(self.exchange, pair);
+    }
+
+    pub fn pair_unsubscribed(&self, pair: &CurrencyPair) {
+        self.context.subscriptions.remove(self.exchange, pair);
+    }
+
+    // A message that could not be handled is skipped rather than taking down the connection
+    pub fn handler_error(&mut self, error: Error) {
+        self.errors += 1;
+        self.context.metrics.handler_failed(self.exchange);
+        error!("Could not handle message ({} so far on this connection): {}", self.errors, error);
+    }
+
+    pub fn deserialize_error(&mut self, error: ::serde_json::Error) {
+        self.context.metrics.deserialize_failed(self.exchange);
+        error!("Could not deserialize message: {}", error);
+    }
+
+    #[allow(clippy::result_large_err)]
+    pub fn close(&mut self) -> ws::Result<()> {
+        match self.exchange_tx {
+            Some(ref exchange_tx) => exchange_tx.close(ws::CloseCode::Away),
+            None => Ok(())
+        }
     }
 
     // Send messages upstream to the API we are consuming from
     pub fn send_upstream(&mut self, msgs: &[String]) -> Result<()> {
+        let exchange_tx = match self.exchange_tx {
+            Some(ref exchange_tx) => exchange_tx,
+            None => {
+                debug!("Dropping {} requests to {} without a connection", msgs.len(), self.exchange);
+                return Ok(());
+            }
+        };
+
         let failures: Vec<Error> = msgs.into_iter().map(|msg| {
-                self.exchange_tx.send(msg.clone())
+                exchange_tx.send(msg.clone())
                     .chain_err(|| ErrorKind::BroadcastError)
             })
             .filter(|result| result.is_err())
             .map(|fail| fail.unwrap_err())
             .collect();
 
-        if failures.len() == 0 {
+        if failures.is_empty() {
             Ok(())
         } else {
             bail!(ErrorKind::MultipleBroadcastError(failures))
@@ -34,47 +203,46 @@ impl HandlerCore {
 
     // Broadcast messages downstream to the consumers listening to our broadcast
     pub fn broadcast(&mut self, broadcast: BroadcastType) -> Result<()> {
-        match broadcast {
+        let broadcasts = match broadcast {
             BroadcastType::None => {
                 trace!("Discarding message - no broadcast required");
 
-                Ok(())
+                return Ok(());
             },
             BroadcastType::One(broadcast) => {
                 trace!("Sending one broadcast");
 
-                let serialized = ::serde_json::to_string(&broadcast)?;
-                self.broadcast_tx.send(serialized)?;
-
-                Ok(())
+                vec!(broadcast)
             },
             BroadcastType::Many(broadcasts) => {
                 trace!("Sending {} broadcasts", broadcasts.len());
 
-                let serialization: Vec<Result<String>> = broadcasts.into_iter()
-                    .map(|broadcast| ::serde_json::to_string(&broadcast)
-                        .chain_err(|| ErrorKind::BroadcastError))
-                    .collect();
-
-                let mut failures = vec!();
-
-                for serialization_result in serialization {
-                    match serialization_result {
-                        Ok(msg) => {
-                            if let Err(e) = self.broadcast_tx.send(msg).chain_err(|| ErrorKind::BroadcastError) {
-                                failures.push(e);
-                            }
-                        },
-                        Err(e) => failures.push(e)
-                    }
-                }
-
-                if failures.len() == 0 {
-                    Ok(())
-                } else {
-                    bail!(ErrorKind::MultipleBroadcastError(failures))
-                }
+                broadcasts
+            }
+        };
+
+        // Subscriptions belong to the connection, so they are cleared here rather than by the server,
+        // which applies everything else to the market state as it dispatches the broadcast
+        for broadcast in &broadcasts {
+            if let Broadcast::ExchangeConnectionClosed { exchange, .. } = *broadcast {
+                self.context.subscriptions.clear(exchange);
+            }
+        }
+
+        let ts = self.timestamp();
+        let mut failures = vec!();
+
+        for broadcast in broadcasts {
+            if let Err(e) = self.context.broadcast_tx.send((broadcast, ts)).chain_err(|| ErrorKind::BroadcastError) {
+                failures.push(e);
             }
         }
+
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            self.context.metrics.broadcasts_failed(self.exchange, failures.len());
+            bail!(ErrorKind::MultipleBroadcastError(failures))
+        }
     }
 }
\ No newline at end of file
@@ -3,26 +3,18 @@ macro_rules! generic_open {
         fn on_open(&mut self, _: ws::Handshake) -> ws::Result<()> {
             info!("Connected to {}", $exch);
 
-            let requests = Self::get_requests(&self.pairs);
+            let requests = self.get_requests();
 
-            while let Err(e) = self.inner.send_upstream(&requests) {
-                // This is a worry - might indicate a busted exchange
+            if let Err(e) = self.inner.send_upstream(&requests) {
                 // All queue messages need to reach the endpoint to guarantee downstream data validity
-
-                error!("Could not send all channel join requests to {}: {}", $exch, e);
-
-                // TODO: check what happens on multiple subscriptions
-
-                info!("Retrying connection to {} in 10 seconds...", $exch);
-                ::std::thread::sleep(::std::time::Duration::from_secs(10));
+                // Closing leaves the connection loop to try again once it has backed off
+                error!("Could not send all channel join requests to {}, closing connection: {}", $exch, e);
+                return self.inner.close();
             }
 
-            let open = Broadcast::ExchangeConnectionOpened {
-                exchange: $exch,
-                ts: consumer::timestamp()
-            };
+            self.inner.start_watchdog()?;
 
-            if let Err(e) = self.inner.broadcast(BroadcastType::One(open)) {
+            if let Err(e) = self.inner.connection_opened($exch) {
                 // May occur on launch when exchange connection is fine but server has not started up yet
                 warn!("Could not broadcast {} open message: {}", $exch, e);
             }
@@ -35,17 +27,22 @@ macro_rules! generic_open {
 macro_rules! generic_on_message {
     ($resp:ty) => {
         fn on_message(&mut self, msg: ws::Message) -> ws::Result<()> {
+            self.inner.message_received();
+
             match msg.into_text() {
                 Ok(txt) => {
-                    match ::serde_json::from_str::<$resp>(&txt) {
-                        Ok(response) => {
-                            let broadcast = self.handle_response(response);
+                    self.inner.capture(&txt);
 
-                            if let Err(e) = self.inner.broadcast(broadcast) {
-                                error!("Could not broadcast message: {}", e);
-                            }
+                    match ::serde_json::from_str::<$resp>(&txt) {
+                        Ok(response) => match self.handle_response(response) {
+                            Ok(broadcast) => {
+                                if let Err(e) = self.inner.broadcast(broadcast) {
+                                    error!("Could not broadcast message: {}", e);
+                                }
+                            },
+                            Err(e) => self.inner.handler_error(e)
                         },
-                        Err(e) => error!("Could not deserialize message: {}", e)
+                        Err(e) => self.inner.deserialize_error(e)
                     }
                 },
                 Err(e) => error!("Could not convert message to text: {}", e)
@@ -63,7 +60,7 @@ macro_rules! generic_on_close {
 
             let closed = Broadcast::ExchangeConnectionClosed {
                 exchange: $exch,
-                ts: consumer::timestamp()
+                ts: self.inner.timestamp()
             };
 
             if let Err(e) = self.inner.broadcast(BroadcastType::One(closed)) {
@@ -75,3 +72,11 @@ macro_rules! generic_on_close {
         }
     }
 }
+
+macro_rules! generic_on_timeout {
+    ($exch:path) => {
+        fn on_timeout(&mut self, event: ws::util::Token) -> ws::Result<()> {
+            self.inner.on_timeout($exch, event)
+        }
+    }
+}
\ No newline at end of file
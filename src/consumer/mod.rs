@@ -2,16 +2,28 @@ pub mod handler;
 #[macro_use]
 pub mod macros;
 
-mod error;
+pub mod error;
 
 use super::domain::*;
+use self::handler::{HandlerContext, HandlerCore};
+use broadcast_api::{Broadcast, ConnectionState, Timestamp};
+use config::{ExchangeConfig, ReconnectConfig};
+use rand::{self, Rng};
 use ws;
-use std::{time, thread};
+use std::{time::{self, Duration}, thread};
+use std::sync::{Arc, atomic::{AtomicI64, Ordering}};
+
+// Connects to the exchange and reconnects whenever the connection is lost or can't be made
+// Consecutive failures back off exponentially, and the exchange is marked as down once there have been too many
+pub fn connect<T: ws::Factory + ConnectionFactory>(context: HandlerContext, pairs: Vec<CurrencyPair>, exchange: &ExchangeConfig) {
+    let exchange = exchange.clone();
 
-pub fn connect<T: ws::Factory + ConnectionFactory>(broadcast_tx: ws::Sender, pairs: Vec<CurrencyPair>) {
     thread::spawn(move || {
+        let mut failures = 0;
+        context.status.set(T::EXCHANGE, ConnectionState::Connecting, failures);
+
         loop {
-            let factory = T::new(broadcast_tx.clone(), pairs.clone());
+            let factory = T::new(context.clone(), pairs.clone(), &exchange);
 
             let settings = {
                 let mut settings = ws::Settings::default();
@@ -22,7 +34,7 @@ pub fn connect<T: ws::Factory + ConnectionFactory>(broadcast_tx: ws::Sender, pai
 
             match ws::Builder::new().with_settings(settings).build(factory) {
                 Ok(mut ws) => {
-                    match ws.connect(T::get_connect_addr()) {
+                    match ws.connect(exchange.addr.clone()) {
                         Ok(_) => {
                             match ws.run() {
                                 Ok(_) => info!("WebSocket connection closed gracefully"),
@@ -37,25 +49,98 @@ pub fn connect<T: ws::Factory + ConnectionFactory>(broadcast_tx: ws::Sender, pai
 
             // We've lost connection to our WebSocket endpoint (or could not build it)
             // Consumers will have been notified of this event through the handler
-            // Delay then attempt a reconnect
-            thread::sleep(time::Duration::from_secs(10));
-            info!("Attempting to reconnect...");
+            // A connection that opened resets the count, so losing it is the first failure
+            let opened = context.status.get(T::EXCHANGE).is_some_and(|(state, _)| state == ConnectionState::Open);
+            failures = if opened { 1 } else { failures + 1 };
+
+            let state = if failures >= context.reconnect.failure_threshold {
+                ConnectionState::Down
+            } else {
+                ConnectionState::Connecting
+            };
+            let previous = context.status.set(T::EXCHANGE, state, failures);
+            if state == ConnectionState::Down && previous != Some(state) {
+                error!("{} is down after {} consecutive connection failures", T::EXCHANGE, failures);
+            }
+
+            let delay = reconnect_delay(&context.reconnect, failures);
+            let ts = context.clock.now();
+            let status = Broadcast::ExchangeStatus {
+                exchange: T::EXCHANGE,
+                state,
+                failures,
+                retry: Some(millis(delay) as i64),
+                ts
+            };
+            if let Err(e) = context.broadcast_tx.send((status, ts)) {
+                warn!("Could not broadcast {} status: {}", T::EXCHANGE, e);
+            }
+
+            info!("Attempting to reconnect to {} in {} ms...", T::EXCHANGE, millis(delay));
+            thread::sleep(delay);
+            context.metrics.reconnecting(T::EXCHANGE);
         }
     });
 }
 
-pub trait ConnectionFactory {
-    fn new(broadcast_tx: ::ws::Sender, pairs: Vec<CurrencyPair>) -> Self;
+// Doubles for each consecutive failure up to the maximum delay
+// Up to half of the delay is then taken off at random, so that reconnects are spread out
+// Once the exchange is down attempts are made at exactly the maximum delay, which keeps them as spread out as they were
+fn reconnect_delay(config: &ReconnectConfig, failures: u32) -> Duration {
+    if failures >= config.failure_threshold {
+        return config.max_delay;
+    }
+    let doublings = failures.saturating_sub(1).min(31);
+    let delay = config.delay.checked_mul(1 << doublings).unwrap_or(config.max_delay).min(config.max_delay);
+    let jitter = rand::thread_rng().gen_range(0, millis(delay) / 2 + 1);
+    delay - Duration::from_millis(jitter)
+}
+
+pub trait ConnectionFactory: ws::Factory {
+    const EXCHANGE: Exchange;
 
-    fn get_connect_addr() -> ::url::Url;
+    fn new(context: HandlerContext, pairs: Vec<CurrencyPair>, exchange: &ExchangeConfig) -> Self;
+
+    // Builds the handler for one connection, which is either a live one or a replay of a recorded one
+    fn handler(&self, inner: HandlerCore) -> Self::Handler;
 }
 
 pub trait MarketHandler {
-    fn get_requests(pairs: &[CurrencyPair]) -> Vec<String>;
+    fn get_requests(&self) -> Vec<String>;
 
     fn stringify_pair(pair: &CurrencyPair) -> String;
 }
 
+// The time broadcasts are stamped with, which is when each recorded frame was received while replaying
+#[derive(Clone, Default)]
+pub struct Clock {
+    replayed: Option<Arc<AtomicI64>>
+}
+
+impl Clock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn replayed() -> Self {
+        Self { replayed: Some(Arc::new(AtomicI64::new(0))) }
+    }
+
+    // Has no effect unless replaying
+    pub fn set(&self, ts: Timestamp) {
+        if let Some(ref replayed) = self.replayed {
+            replayed.store(ts, Ordering::SeqCst);
+        }
+    }
+
+    pub fn now(&self) -> Timestamp {
+        match self.replayed {
+            Some(ref replayed) => replayed.load(Ordering::SeqCst),
+            None => timestamp()
+        }
+    }
+}
+
 pub fn standardise_value(value: f64) -> i64 {
     (value * f64::from(::MULTIPLIER)).round() as i64
 }
@@ -66,4 +151,57 @@ pub fn timestamp() -> i64 {
         .expect("Time went backwards");
 
     (time.as_secs() * 1000) as i64
+}
+
+pub fn millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + u64::from(duration.subsec_millis())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(failure_threshold: u32) -> ReconnectConfig {
+        ReconnectConfig {
+            delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            failure_threshold,
+            stale_after: Duration::from_secs(30)
+        }
+    }
+
+    // The range of delays, sampled often enough to see the jitter at both ends
+    fn delays(config: &ReconnectConfig, failures: u32) -> (u64, u64) {
+        let delays: Vec<u64> = (0..2000).map(|_| millis(reconnect_delay(config, failures))).collect();
+        (*delays.iter().min().unwrap(), *delays.iter().max().unwrap())
+    }
+
+    #[test]
+    fn first_failures_use_the_initial_delay() {
+        let config = config(10);
+        for &failures in &[0, 1] {
+            let (shortest, longest) = delays(&config, failures);
+            assert!(shortest >= 500 && longest <= 1000, "{} failures gave {}..{} ms", failures, shortest, longest);
+        }
+    }
+
+    #[test]
+    fn doubles_up_to_the_maximum_delay() {
+        let config = config(10);
+        for &(failures, delay) in &[(2, 2000), (3, 4000), (6, 32000), (7, 60000), (9, 60000)] {
+            let (shortest, longest) = delays(&config, failures);
+            assert!(shortest >= delay / 2 && longest <= delay, "{} failures gave {}..{} ms", failures, shortest, longest);
+            // Jitter takes off up to half the delay, so both ends of the range are reached
+            assert!(shortest < delay * 3 / 5 && longest > delay * 9 / 10, "{} failures gave {}..{} ms", failures, shortest, longest);
+        }
+    }
+
+    #[test]
+    fn waits_the_maximum_delay_while_down() {
+        let config = config(3);
+        assert!(millis(reconnect_delay(&config, 2)) <= 2000);
+        for &failures in &[3, 4, 40, u32::MAX] {
+            assert_eq!(delays(&config, failures), (60000, 60000));
+        }
+    }
 }
\ No newline at end of file
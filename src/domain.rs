@@ -1,40 +1,716 @@
+use broadcast_api::{Broadcast, ConnectionState, ConsolidatedLevel, Order, OrderId, Price, Side, Timestamp, TradeDetails, VenueTop, Volume};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::fmt;
+use std::sync::{Arc, RwLock};
 
-#[derive(Debug, Serialize, Copy, Clone, PartialEq, Ord, Eq, PartialOrd)]
-pub enum CurrencyPair {
-    XRPBTC
+// Price levels on one side of a book, best first
+type Levels = Vec<(Price, Volume)>;
+
+#[derive(Debug, Clone, PartialEq, Ord, Eq, PartialOrd, Hash)]
+pub struct CurrencyPair {
+    pub base: String,
+    pub quote: String
 }
 
 impl CurrencyPair {
-    pub fn parse(values: &str) -> Vec<CurrencyPair> {
-        let mut pairs: Vec<CurrencyPair> = values.split(',')
-            .map(|x| Self::map(x).expect(&format!("Could not parse currency pair {}", x)))
-            .collect();
-        pairs.sort_unstable();
-        pairs.dedup();
-        pairs
+    pub fn new(base: &str, quote: &str) -> Self {
+        Self { base: base.trim().to_uppercase(), quote: quote.trim().to_uppercase() }
     }
 
+    // Pairs are written as BASE/QUOTE, for example XRP/BTC
     pub fn map(value: &str) -> Option<CurrencyPair> {
-        match value {
-            "BTCXRP" => Some(CurrencyPair::XRPBTC),
+        let mut assets = value.trim().split('/');
+        match (assets.next(), assets.next(), assets.next()) {
+            (Some(base), Some(quote), None) if !base.trim().is_empty() && !quote.trim().is_empty() =>
+                Some(Self::new(base, quote)),
             _ => None
         }
     }
 }
 
-#[derive(Debug, Serialize)]
+impl fmt::Display for CurrencyPair {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        write!(f, "{}/{}", self.base, self.quote)
+    }
+}
+
+impl Serialize for CurrencyPair {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for CurrencyPair {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        Self::map(&value).ok_or_else(|| de::Error::custom(format!("could not parse currency pair {}", value)))
+    }
+}
+
+// Exchanges that name an asset differently list their codes as (standard code, exchange code)
+pub type AssetCodes = [(&'static str, &'static str)];
+
+pub fn exchange_asset_code(asset: &str, codes: &AssetCodes) -> String {
+    codes.iter()
+        .find(|&&(standard, _)| standard == asset)
+        .map(|&(_, exchange)| exchange.to_string())
+        .unwrap_or_else(|| asset.to_string())
+}
+
+// Maps the symbols an exchange uses back to the configured currency pairs
+pub struct SymbolTable {
+    pairs: HashMap<String, CurrencyPair>
+}
+
+impl SymbolTable {
+    pub fn new(pairs: &[CurrencyPair], stringify: fn(&CurrencyPair) -> String) -> Self {
+        Self { pairs: pairs.iter().map(|pair| (stringify(pair), pair.clone())).collect() }
+    }
+
+    pub fn pair(&self, symbol: &str) -> Option<&CurrencyPair> {
+        self.pairs.get(symbol)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[serde(rename_all = "lowercase")]
 pub enum Exchange {
     BtcMarkets,
-    Bitfinex
+    Bitfinex,
+    Poloniex
+}
+
+impl Exchange {
+    // Exchanges are named in lowercase, as they are serialized
+    pub fn map(value: &str) -> Option<Exchange> {
+        match value.trim().to_lowercase().as_str() {
+            "btcmarkets" => Some(Exchange::BtcMarkets),
+            "bitfinex" => Some(Exchange::Bitfinex),
+            "poloniex" => Some(Exchange::Poloniex),
+            _ => None
+        }
+    }
 }
 
 impl fmt::Display for Exchange {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
         match self {
             Exchange::BtcMarkets => write!(f, "BTCMarkets"),
-            Exchange::Bitfinex => write!(f, "Bitfinex")
+            Exchange::Bitfinex => write!(f, "Bitfinex"),
+            Exchange::Poloniex => write!(f, "Poloniex")
+        }
+    }
+}
+
+// Price levels for one side of the book - a level is removed once it has no volume left
+#[derive(Debug, Clone, Default)]
+pub struct OrderBook {
+    bids: BTreeMap<Price, Volume>,
+    asks: BTreeMap<Price, Volume>
+}
+
+impl OrderBook {
+    pub fn replace(&mut self, bids: &[(Price, Volume)], asks: &[(Price, Volume)]) {
+        self.bids.clear();
+        self.asks.clear();
+        self.update(bids, asks);
+    }
+
+    pub fn update(&mut self, bids: &[(Price, Volume)], asks: &[(Price, Volume)]) {
+        for &(price, volume) in bids {
+            self.set_bid(price, volume);
+        }
+        for &(price, volume) in asks {
+            self.set_ask(price, volume);
+        }
+    }
+
+    pub fn remove(&mut self, bids: &[(Price, Volume)], asks: &[(Price, Volume)]) {
+        for &(price, _) in bids {
+            self.bids.remove(&price);
+        }
+        for &(price, _) in asks {
+            self.asks.remove(&price);
+        }
+    }
+
+    pub fn set_bid(&mut self, price: Price, volume: Volume) {
+        set_level(&mut self.bids, price, volume);
+    }
+
+    pub fn set_ask(&mut self, price: Price, volume: Volume) {
+        set_level(&mut self.asks, price, volume);
+    }
+
+    pub fn bid(&self, price: Price) -> Volume {
+        self.bids.get(&price).cloned().unwrap_or(0)
+    }
+
+    pub fn ask(&self, price: Price) -> Volume {
+        self.asks.get(&price).cloned().unwrap_or(0)
+    }
+
+    pub fn best_bid(&self) -> Option<(Price, Volume)> {
+        self.bids.iter().next_back().map(|(&price, &volume)| (price, volume))
+    }
+
+    pub fn best_ask(&self) -> Option<(Price, Volume)> {
+        self.asks.iter().next().map(|(&price, &volume)| (price, volume))
+    }
+
+    // Bids are ordered from the highest price down, asks from the lowest price up
+    pub fn top(&self, depth: usize) -> (Levels, Levels) {
+        (self.bids.iter().rev().take(depth).map(|(&price, &volume)| (price, volume)).collect(),
+         self.asks.iter().take(depth).map(|(&price, &volume)| (price, volume)).collect())
+    }
+
+    pub fn is_crossed(&self) -> bool {
+        match (self.best_bid(), self.best_ask()) {
+            (Some((bid, _)), Some((ask, _))) => bid >= ask,
+            _ => false
         }
     }
+}
+
+fn set_level(levels: &mut BTreeMap<Price, Volume>, price: Price, volume: Volume) {
+    if volume == 0 {
+        levels.remove(&price);
+    } else {
+        levels.insert(price, volume);
+    }
+}
+
+// The latest orderbook for every exchange and pair, kept by the server from the broadcasts it dispatches
+#[derive(Debug, Clone, Default)]
+pub struct BookStore {
+    books: Arc<RwLock<HashMap<(Exchange, CurrencyPair), OrderBook>>>
+}
+
+impl BookStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Returns whether the top of the book changed
+    pub fn update<F: FnOnce(&mut OrderBook)>(&self, exchange: Exchange, pair: &CurrencyPair, f: F) -> bool {
+        let mut books = self.books.write().expect("Book store lock was poisoned");
+        let book = books.entry((exchange, pair.clone())).or_default();
+
+        let top = (book.best_bid(), book.best_ask());
+        let was_crossed = book.is_crossed();
+        f(book);
+
+        // Books cross briefly in normal races between exchange updates, so this is only logged as it happens
+        if book.is_crossed() && !was_crossed {
+            warn!("{} {} orderbook is crossed: best bid {:?}, best ask {:?}",
+                  exchange, pair, book.best_bid(), book.best_ask());
+        }
+
+        top != (book.best_bid(), book.best_ask())
+    }
+
+    // Books are no longer valid once the connection to their exchange is lost
+    // Returns the pairs that had a book on the exchange
+    pub fn clear(&self, exchange: Exchange) -> Vec<CurrencyPair> {
+        let mut books = self.books.write().expect("Book store lock was poisoned");
+        let pairs = books.keys()
+            .filter(|&&(book_exchange, _)| book_exchange == exchange)
+            .map(|(_, pair)| pair.clone())
+            .collect();
+        books.retain(|&(book_exchange, _), _| book_exchange != exchange);
+        pairs
+    }
+
+    // Drops a single book that has drifted from the exchange until it is rebuilt from a new snapshot
+    pub fn remove(&self, exchange: Exchange, pair: &CurrencyPair) -> bool {
+        self.books.write().expect("Book store lock was poisoned").remove(&(exchange, pair.clone())).is_some()
+    }
+
+    pub fn keys(&self) -> Vec<(Exchange, CurrencyPair)> {
+        self.books.read().expect("Book store lock was poisoned").keys().cloned().collect()
+    }
+
+    pub fn book(&self, exchange: Exchange, pair: &CurrencyPair) -> Option<OrderBook> {
+        self.books.read().expect("Book store lock was poisoned")
+            .get(&(exchange, pair.clone()))
+            .cloned()
+    }
+
+    // Best bid and ask of every exchange with a book for the pair
+    pub fn tops(&self, pair: &CurrencyPair) -> Vec<VenueTop> {
+        let books = self.books.read().expect("Book store lock was poisoned");
+
+        let mut tops: Vec<VenueTop> = books.iter()
+            .filter(|((_, book_pair), _)| book_pair == pair)
+            .map(|(&(exchange, _), book)| (exchange, book.best_bid(), book.best_ask()))
+            .collect();
+        tops.sort_unstable();
+        tops
+    }
+
+    // The books of every exchange for a pair merged into a single ladder
+    pub fn consolidated(&self, pair: &CurrencyPair) -> (Vec<ConsolidatedLevel>, Vec<ConsolidatedLevel>) {
+        let books = self.books.read().expect("Book store lock was poisoned");
+
+        let (mut bids, mut asks) = (BTreeMap::new(), BTreeMap::new());
+        for (&(exchange, ref book_pair), book) in books.iter() {
+            if book_pair != pair {
+                continue;
+            }
+            for (&price, &volume) in &book.bids {
+                bids.entry(price).or_insert_with(Vec::new).push((exchange, volume));
+            }
+            for (&price, &volume) in &book.asks {
+                asks.entry(price).or_insert_with(Vec::new).push((exchange, volume));
+            }
+        }
+
+        (bids.into_iter().rev().map(|(price, volumes)| consolidate_level(price, volumes)).collect(),
+         asks.into_iter().map(|(price, volumes)| consolidate_level(price, volumes)).collect())
+    }
+
+    // Consolidated state of specific price levels - levels with no volume left on any exchange have an empty breakdown
+    pub fn consolidated_levels(&self, pair: &CurrencyPair, bid_prices: &[Price], ask_prices: &[Price])
+                               -> (Vec<ConsolidatedLevel>, Vec<ConsolidatedLevel>) {
+        let books = self.books.read().expect("Book store lock was poisoned");
+
+        let level = |price: Price, volume_at: &dyn Fn(&OrderBook) -> Volume| {
+            let volumes = books.iter()
+                .filter(|((_, book_pair), _)| book_pair == pair)
+                .map(|(&(exchange, _), book)| (exchange, volume_at(book)))
+                .filter(|&(_, volume)| volume != 0)
+                .collect();
+            consolidate_level(price, volumes)
+        };
+
+        (bid_prices.iter().map(|&price| level(price, &|book| book.bid(price))).collect(),
+         ask_prices.iter().map(|&price| level(price, &|book| book.ask(price))).collect())
+    }
+}
+
+fn consolidate_level(price: Price, mut volumes: Vec<(Exchange, Volume)>) -> ConsolidatedLevel {
+    volumes.sort_unstable();
+    let total = volumes.iter().map(|&(_, volume)| volume).sum();
+    (price, total, volumes)
+}
+
+// Individual orders on a book, for exchanges that report them
+#[derive(Debug, Clone, Default)]
+pub struct OrderList {
+    orders: HashMap<OrderId, (Side, Price, Volume)>
+}
+
+impl OrderList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn replace(&mut self, bids: &[Order], asks: &[Order]) {
+        self.orders.clear();
+        for &order in bids {
+            self.insert(Side::Bid, order);
+        }
+        for &order in asks {
+            self.insert(Side::Ask, order);
+        }
+    }
+
+    pub fn insert(&mut self, side: Side, (order_id, price, volume): Order) {
+        self.orders.insert(order_id, (side, price, volume));
+    }
+
+    pub fn remove(&mut self, order_id: OrderId) {
+        self.orders.remove(&order_id);
+    }
+
+    // Bids are ordered from the highest price down, asks from the lowest price up
+    // Order IDs are assigned as orders are placed, so orders at the same price are in queue order
+    pub fn ranked(&self) -> (Vec<Order>, Vec<Order>) {
+        let (mut bids, mut asks) = (vec!(), vec!());
+        for (&order_id, &(side, price, volume)) in &self.orders {
+            match side {
+                Side::Bid => bids.push((order_id, price, volume)),
+                Side::Ask => asks.push((order_id, price, volume))
+            }
+        }
+        bids.sort_unstable_by_key(|&(order_id, price, _)| (-price, order_id));
+        asks.sort_unstable_by_key(|&(order_id, price, _)| (price, order_id));
+        (bids, asks)
+    }
+}
+
+// The latest individual orders for every exchange and pair that reports them
+#[derive(Debug, Clone, Default)]
+pub struct OrderStore {
+    orders: Arc<RwLock<HashMap<(Exchange, CurrencyPair), OrderList>>>
+}
+
+impl OrderStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn update<F: FnOnce(&mut OrderList)>(&self, exchange: Exchange, pair: &CurrencyPair, f: F) {
+        let mut orders = self.orders.write().expect("Order store lock was poisoned");
+        f(orders.entry((exchange, pair.clone())).or_default());
+    }
+
+    pub fn clear(&self, exchange: Exchange) {
+        self.orders.write().expect("Order store lock was poisoned")
+            .retain(|&(list_exchange, _), _| list_exchange != exchange);
+    }
+
+    pub fn remove(&self, exchange: Exchange, pair: &CurrencyPair) {
+        self.orders.write().expect("Order store lock was poisoned").remove(&(exchange, pair.clone()));
+    }
+
+    pub fn keys(&self) -> Vec<(Exchange, CurrencyPair)> {
+        self.orders.read().expect("Order store lock was poisoned").keys().cloned().collect()
+    }
+
+    pub fn orders(&self, exchange: Exchange, pair: &CurrencyPair) -> Option<OrderList> {
+        self.orders.read().expect("Order store lock was poisoned")
+            .get(&(exchange, pair.clone()))
+            .cloned()
+    }
+}
+
+// Connection state and consecutive connection failures of every exchange
+#[derive(Debug, Clone, Default)]
+pub struct StatusStore {
+    statuses: Arc<RwLock<HashMap<Exchange, (ConnectionState, u32)>>>
+}
+
+impl StatusStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Returns the previous state of the exchange
+    pub fn set(&self, exchange: Exchange, state: ConnectionState, failures: u32) -> Option<ConnectionState> {
+        self.statuses.write().expect("Status store lock was poisoned")
+            .insert(exchange, (state, failures))
+            .map(|(previous, _)| previous)
+    }
+
+    pub fn get(&self, exchange: Exchange) -> Option<(ConnectionState, u32)> {
+        self.statuses.read().expect("Status store lock was poisoned").get(&exchange).cloned()
+    }
+
+    pub fn all(&self) -> Vec<(Exchange, ConnectionState, u32)> {
+        let mut statuses: Vec<(Exchange, ConnectionState, u32)> = self.statuses.read().expect("Status store lock was poisoned")
+            .iter()
+            .map(|(&exchange, &(state, failures))| (exchange, state, failures))
+            .collect();
+        statuses.sort_unstable_by_key(|&(exchange, _, _)| exchange);
+        statuses
+    }
+}
+
+// Pairs each exchange has confirmed the subscriptions for on its current connection
+#[derive(Debug, Clone, Default)]
+pub struct SubscriptionStore {
+    subscriptions: Arc<RwLock<HashMap<Exchange, HashSet<CurrencyPair>>>>
+}
+
+impl SubscriptionStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&self, exchange: Exchange, pair: &CurrencyPair) {
+        self.subscriptions.write().expect("Subscription store lock was poisoned")
+            .entry(exchange)
+            .or_default()
+            .insert(pair.clone());
+    }
+
+    pub fn remove(&self, exchange: Exchange, pair: &CurrencyPair) {
+        if let Some(pairs) = self.subscriptions.write().expect("Subscription store lock was poisoned").get_mut(&exchange) {
+            pairs.remove(pair);
+        }
+    }
+
+    pub fn clear(&self, exchange: Exchange) {
+        self.subscriptions.write().expect("Subscription store lock was poisoned").remove(&exchange);
+    }
+
+    pub fn contains(&self, exchange: Exchange, pair: &CurrencyPair) -> bool {
+        self.subscriptions.read().expect("Subscription store lock was poisoned")
+            .get(&exchange)
+            .is_some_and(|pairs| pairs.contains(pair))
+    }
+}
+
+const RECENT_TRADES_LIMIT: usize = 100;
+
+type RecentTrades = VecDeque<TradeDetails>;
+
+// The most recent trades for every exchange and pair, oldest first
+#[derive(Debug, Clone, Default)]
+pub struct TradeStore {
+    trades: Arc<RwLock<HashMap<(Exchange, CurrencyPair), RecentTrades>>>
+}
+
+impl TradeStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn replace(&self, exchange: Exchange, pair: &CurrencyPair, trades: &[TradeDetails]) {
+        let mut recent: RecentTrades = trades.iter().cloned().collect();
+        while recent.len() > RECENT_TRADES_LIMIT {
+            recent.pop_front();
+        }
+        self.trades.write().expect("Trade store lock was poisoned").insert((exchange, pair.clone()), recent);
+    }
+
+    pub fn push(&self, exchange: Exchange, pair: &CurrencyPair, trade: TradeDetails) {
+        let mut trades = self.trades.write().expect("Trade store lock was poisoned");
+        let recent = trades.entry((exchange, pair.clone())).or_default();
+        recent.push_back(trade);
+        if recent.len() > RECENT_TRADES_LIMIT {
+            recent.pop_front();
+        }
+    }
+
+    pub fn keys(&self) -> Vec<(Exchange, CurrencyPair)> {
+        self.trades.read().expect("Trade store lock was poisoned").keys().cloned().collect()
+    }
+
+    pub fn recent(&self, exchange: Exchange, pair: &CurrencyPair) -> Vec<TradeDetails> {
+        self.trades.read().expect("Trade store lock was poisoned")
+            .get(&(exchange, pair.clone()))
+            .map(|recent| recent.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+// Orderbooks, individual orders and recent trades maintained from the broadcasts sent to consumers
+#[derive(Debug, Clone)]
+pub struct MarketState {
+    pub books: BookStore,
+    pub orders: OrderStore,
+    pub trades: TradeStore
+}
+
+impl MarketState {
+    pub fn new() -> Self {
+        Self { books: BookStore::new(), orders: OrderStore::new(), trades: TradeStore::new() }
+    }
+
+    // Keep the local orderbooks in step with what is being broadcast
+    // Updates set the volume of a price level and removals delete the price level
+    // Returns any further broadcasts resulting from the change, such as the consolidated book, stamped with the given time
+    pub fn record(&self, broadcast: &Broadcast, ts: Timestamp) -> Vec<Broadcast> {
+        match *broadcast {
+            Broadcast::OrderbookSnapshot { source, ref pair, ref bids, ref asks } => {
+                let top_changed = self.books.update(source, pair, |book| book.replace(bids, asks));
+                self.with_best_bid_offer(pair, top_changed, ts, self.consolidated_snapshot(pair))
+            },
+            Broadcast::OrderbookUpdate { source, ref pair, ref bids, ref asks } => {
+                let top_changed = self.books.update(source, pair, |book| book.update(bids, asks));
+                self.with_best_bid_offer(pair, top_changed, ts, self.consolidated_update(pair, bids, asks))
+            },
+            Broadcast::OrderbookRemove { source, ref pair, ref bids, ref asks } => {
+                let top_changed = self.books.update(source, pair, |book| book.remove(bids, asks));
+                self.with_best_bid_offer(pair, top_changed, ts, self.consolidated_update(pair, bids, asks))
+            },
+            Broadcast::OrderbookInvalidated { source, ref pair, .. } => {
+                self.orders.remove(source, pair);
+                let top_changed = self.books.remove(source, pair);
+                self.with_best_bid_offer(pair, top_changed, ts, self.consolidated_snapshot(pair))
+            },
+            Broadcast::OrderSnapshot { source, ref pair, ref bids, ref asks } => {
+                self.orders.update(source, pair, |orders| orders.replace(bids, asks));
+                vec!()
+            },
+            Broadcast::OrderAdded { source, ref pair, side, order } |
+            Broadcast::OrderModified { source, ref pair, side, order } => {
+                self.orders.update(source, pair, |orders| orders.insert(side, order));
+                vec!()
+            },
+            Broadcast::OrderCancelled { source, ref pair, order: (order_id, _, _), .. } => {
+                self.orders.update(source, pair, |orders| orders.remove(order_id));
+                vec!()
+            },
+            Broadcast::TradeSnapshot { source, ref pair, ref trades } => {
+                self.trades.replace(source, pair, trades);
+                vec!()
+            },
+            Broadcast::Trade { source, ref pair, trade } => {
+                self.trades.push(source, pair, trade);
+                vec!()
+            },
+            Broadcast::ExchangeConnectionClosed { exchange, .. } => {
+                self.orders.clear(exchange);
+                self.books.clear(exchange).iter()
+                    .flat_map(|pair| self.with_best_bid_offer(pair, true, ts, self.consolidated_snapshot(pair)))
+                    .collect()
+            },
+            _ => vec!()
+        }
+    }
+
+    fn with_best_bid_offer(&self, pair: &CurrencyPair, top_changed: bool, ts: Timestamp, broadcast: Broadcast) -> Vec<Broadcast> {
+        if top_changed {
+            vec!(broadcast, self.best_bid_offer(pair, ts))
+        } else {
+            vec!(broadcast)
+        }
+    }
+
+    fn best_bid_offer(&self, pair: &CurrencyPair, ts: Timestamp) -> Broadcast {
+        Broadcast::best_bid_offer(pair.clone(), self.books.tops(pair), ts)
+    }
+
+    fn consolidated_snapshot(&self, pair: &CurrencyPair) -> Broadcast {
+        let (bids, asks) = self.books.consolidated(pair);
+        Broadcast::ConsolidatedOrderbookSnapshot { pair: pair.clone(), bids, asks }
+    }
+
+    fn consolidated_update(&self, pair: &CurrencyPair, bids: &[(Price, Volume)], asks: &[(Price, Volume)]) -> Broadcast {
+        let bid_prices: Vec<Price> = bids.iter().map(|&(price, _)| price).collect();
+        let ask_prices: Vec<Price> = asks.iter().map(|&(price, _)| price).collect();
+        let (bids, asks) = self.books.consolidated_levels(pair, &bid_prices, &ask_prices);
+        Broadcast::ConsolidatedOrderbookUpdate { pair: pair.clone(), bids, asks }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair() -> CurrencyPair {
+        CurrencyPair::new("XRP", "BTC")
+    }
+
+    fn book(bids: &[(Price, Volume)], asks: &[(Price, Volume)]) -> OrderBook {
+        let mut book = OrderBook::default();
+        book.replace(bids, asks);
+        book
+    }
+
+    #[test]
+    fn truncates_the_book_to_depth() {
+        let book = book(&[(97, 1), (99, 2), (98, 3)], &[(102, 4), (100, 5), (101, 6)]);
+
+        assert_eq!(book.top(2), (vec!((99, 2), (98, 3)), vec!((100, 5), (101, 6))));
+        assert_eq!(book.top(0), (vec!(), vec!()));
+        assert_eq!(book.top(usize::MAX), (vec!((99, 2), (98, 3), (97, 1)), vec!((100, 5), (101, 6), (102, 4))));
+    }
+
+    #[test]
+    fn updates_and_removes_levels() {
+        let mut book = book(&[(99, 2), (98, 3)], &[(100, 5)]);
+
+        book.update(&[(99, 0), (97, 1)], &[(100, 7)]);
+        assert_eq!(book.top(usize::MAX), (vec!((98, 3), (97, 1)), vec!((100, 7))));
+
+        book.remove(&[(98, 3)], &[(100, 7)]);
+        assert_eq!(book.best_bid(), Some((97, 1)));
+        assert_eq!(book.best_ask(), None);
+    }
+
+    #[test]
+    fn reports_whether_the_top_changed() {
+        let books = BookStore::new();
+
+        assert!(books.update(Exchange::Bitfinex, &pair(), |book| book.replace(&[(99, 2)], &[(100, 5)])));
+        assert!(!books.update(Exchange::Bitfinex, &pair(), |book| book.update(&[(98, 3)], &[(101, 1)])));
+        assert!(books.update(Exchange::Bitfinex, &pair(), |book| book.update(&[(99, 1)], &[])));
+    }
+    fn merged_books() -> BookStore {
+        let books = BookStore::new();
+        books.update(Exchange::Poloniex, &pair(), |book| book.replace(&[(99, 2), (98, 1)], &[(100, 3)]));
+        books.update(Exchange::Bitfinex, &pair(), |book| book.replace(&[(99, 5)], &[(100, 1), (101, 4)]));
+        books.update(Exchange::BtcMarkets, &pair(), |book| book.replace(&[(97, 6)], &[(100, 2)]));
+        books.update(Exchange::Bitfinex, &CurrencyPair::new("ETH", "BTC"), |book| book.replace(&[(99, 9)], &[]));
+        books
+    }
+
+    #[test]
+    fn merges_levels_across_exchanges() {
+        let (bids, asks) = merged_books().consolidated(&pair());
+
+        assert_eq!(bids, vec!(
+            (99, 7, vec!((Exchange::Bitfinex, 5), (Exchange::Poloniex, 2))),
+            (98, 1, vec!((Exchange::Poloniex, 1))),
+            (97, 6, vec!((Exchange::BtcMarkets, 6)))));
+        assert_eq!(asks, vec!(
+            (100, 6, vec!((Exchange::BtcMarkets, 2), (Exchange::Bitfinex, 1), (Exchange::Poloniex, 3))),
+            (101, 4, vec!((Exchange::Bitfinex, 4)))));
+    }
+
+    #[test]
+    fn consolidates_specific_levels() {
+        let books = merged_books();
+        books.update(Exchange::Poloniex, &pair(), |book| book.remove(&[(99, 2)], &[]));
+
+        let (bids, asks) = books.consolidated_levels(&pair(), &[99, 96], &[101]);
+
+        assert_eq!(bids, vec!((99, 5, vec!((Exchange::Bitfinex, 5))), (96, 0, vec!())));
+        assert_eq!(asks, vec!((101, 4, vec!((Exchange::Bitfinex, 4)))));
+    }
+    // The best bid and best ask across exchanges
+    type Best = (Option<(Exchange, Price, Volume)>, Option<(Exchange, Price, Volume)>);
+
+    fn best_bid_offer(broadcasts: &[Broadcast]) -> Option<Best> {
+        broadcasts.iter()
+            .filter_map(|broadcast| match *broadcast {
+                Broadcast::BestBidOffer { bid, ask, .. } => Some((bid, ask)),
+                _ => None
+            })
+            .next()
+    }
+
+    fn snapshot(source: Exchange, bids: &[(Price, Volume)], asks: &[(Price, Volume)]) -> Broadcast {
+        Broadcast::OrderbookSnapshot { source, pair: pair(), bids: bids.to_vec(), asks: asks.to_vec() }
+    }
+
+    #[test]
+    fn breaks_price_ties_on_volume() {
+        let state = MarketState::new();
+        state.record(&snapshot(Exchange::Poloniex, &[(99, 2)], &[(100, 3)]), 1);
+        let broadcasts = state.record(&snapshot(Exchange::Bitfinex, &[(99, 5)], &[(100, 1)]), 2);
+
+        assert_eq!(best_bid_offer(&broadcasts), Some((Some((Exchange::Bitfinex, 99, 5)), Some((Exchange::Poloniex, 100, 3)))));
+    }
+
+    #[test]
+    fn changes_the_best_bid_offer_on_removal() {
+        let state = MarketState::new();
+        state.record(&snapshot(Exchange::Poloniex, &[(98, 2)], &[(101, 3)]), 1);
+        state.record(&snapshot(Exchange::Bitfinex, &[(99, 5), (97, 1), (96, 1)], &[(100, 1)]), 2);
+
+        let removal = Broadcast::OrderbookRemove { source: Exchange::Bitfinex, pair: pair(), bids: vec!((99, 5)), asks: vec!() };
+        let broadcasts = state.record(&removal, 3);
+        assert_eq!(best_bid_offer(&broadcasts), Some((Some((Exchange::Poloniex, 98, 2)), Some((Exchange::Bitfinex, 100, 1)))));
+        assert_eq!(broadcasts[0], Broadcast::ConsolidatedOrderbookUpdate { pair: pair(), bids: vec!((99, 0, vec!())), asks: vec!() });
+
+        // Removing a level below the top leaves the best bid and offer alone
+        let removal = Broadcast::OrderbookRemove { source: Exchange::Bitfinex, pair: pair(), bids: vec!((96, 1)), asks: vec!() };
+        assert_eq!(best_bid_offer(&state.record(&removal, 4)), None);
+    }
+
+    #[test]
+    fn clears_books_and_orders_when_the_exchange_connection_closes() {
+        let state = MarketState::new();
+        state.record(&snapshot(Exchange::Poloniex, &[(98, 2)], &[(101, 3)]), 1);
+        state.record(&snapshot(Exchange::Bitfinex, &[(99, 5)], &[(100, 1)]), 2);
+        state.record(&Broadcast::OrderSnapshot { source: Exchange::Bitfinex, pair: pair(), bids: vec!((7, 99, 5)), asks: vec!((8, 100, 1)) }, 3);
+
+        let broadcasts = state.record(&Broadcast::ExchangeConnectionClosed { exchange: Exchange::Bitfinex, ts: 4 }, 4);
+
+        assert!(state.books.book(Exchange::Bitfinex, &pair()).is_none());
+        assert!(state.orders.orders(Exchange::Bitfinex, &pair()).is_none());
+        assert!(state.books.book(Exchange::Poloniex, &pair()).is_some());
+        assert_eq!(broadcasts[0], Broadcast::ConsolidatedOrderbookSnapshot {
+            pair: pair(),
+            bids: vec!((98, 2, vec!((Exchange::Poloniex, 2)))),
+            asks: vec!((101, 3, vec!((Exchange::Poloniex, 3))))
+        });
+        assert_eq!(best_bid_offer(&broadcasts), Some((Some((Exchange::Poloniex, 98, 2)), Some((Exchange::Poloniex, 101, 3)))));
+    }
 }
\ No newline at end of file
@@ -0,0 +1,81 @@
+use broadcast_api::ConnectionState;
+use domain::{CurrencyPair, Exchange, StatusStore, SubscriptionStore};
+use metrics::Metrics;
+use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
+use std::time::Duration;
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HealthReport {
+    // The broadcast server is accepting connections
+    pub listening: bool,
+    // Listening, with every required exchange ready
+    pub ready: bool,
+    pub exchanges: Vec<ExchangeHealth>
+}
+
+// An exchange is ready when its connection is open, every pair is subscribed and it has been heard from recently
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExchangeHealth {
+    exchange: Exchange,
+    required: bool,
+    state: Option<ConnectionState>,
+    failures: u32,
+    subscribed: Vec<CurrencyPair>,
+    unsubscribed: Vec<CurrencyPair>,
+    // Milliseconds since anything was received from the exchange
+    last_message_age: Option<u64>,
+    ready: bool
+}
+
+#[derive(Clone)]
+pub struct Health {
+    pub listening: Arc<AtomicBool>,
+    pub status: StatusStore,
+    pub subscriptions: SubscriptionStore,
+    pub metrics: Metrics,
+    // Every enabled exchange, and whether it is required for the aggregator to be ready
+    pub exchanges: Vec<(Exchange, bool)>,
+    pub pairs: Vec<CurrencyPair>,
+    pub stale_after: Duration
+}
+
+impl Health {
+    pub fn report(&self) -> HealthReport {
+        let listening = self.listening.load(Ordering::SeqCst);
+        let exchanges: Vec<ExchangeHealth> = self.exchanges.iter()
+            .map(|&(exchange, required)| self.exchange(exchange, required))
+            .collect();
+        let ready = listening && exchanges.iter().all(|exchange| exchange.ready || !exchange.required);
+
+        HealthReport { listening, ready, exchanges }
+    }
+
+    fn exchange(&self, exchange: Exchange, required: bool) -> ExchangeHealth {
+        let (state, failures) = match self.status.get(exchange) {
+            Some((state, failures)) => (Some(state), failures),
+            None => (None, 0)
+        };
+
+        let (subscribed, unsubscribed): (Vec<CurrencyPair>, Vec<CurrencyPair>) = self.pairs.iter().cloned()
+            .partition(|pair| self.subscriptions.contains(exchange, pair));
+
+        let age = self.metrics.last_message_age(exchange);
+
+        let ready = state == Some(ConnectionState::Open)
+            && unsubscribed.is_empty()
+            && age.is_some_and(|age| age < self.stale_after);
+
+        ExchangeHealth {
+            exchange,
+            required,
+            state,
+            failures,
+            subscribed,
+            unsubscribed,
+            last_message_age: age.map(::consumer::millis),
+            ready
+        }
+    }
+}
\ No newline at end of file
@@ -0,0 +1,28 @@
+pub mod health;
+pub mod server;
+
+use broadcast_api::ConnectionState;
+use domain::{CurrencyPair, Exchange};
+
+// Prices and volumes are scaled by the multiplier, as announced to WebSocket clients when they connect
+#[derive(Debug, Serialize)]
+pub struct Scaled<'a, T: 'a> {
+    #[serde(flatten)]
+    pub body: &'a T,
+    pub multiplier: i32
+}
+
+#[derive(Debug, Serialize)]
+pub struct Exchanges {
+    pub exchanges: Vec<ExchangeSummary>
+}
+
+// Connection state of an exchange along with the pairs it has a book for
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExchangeSummary {
+    pub exchange: Exchange,
+    pub state: ConnectionState,
+    pub failures: u32,
+    pub books: Vec<CurrencyPair>
+}
\ No newline at end of file
@@ -0,0 +1,180 @@
+use super::{ExchangeSummary, Exchanges, Scaled};
+use super::health::Health;
+use broadcast_api::Broadcast;
+use broadcast_api::clients::Clients;
+use domain::{BookStore, CurrencyPair, Exchange, StatusStore, TradeStore};
+use metrics::Metrics;
+use std::io::Cursor;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::thread;
+use tiny_http::{self, Header, Method, Request, Response};
+
+const PROMETHEUS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+const JSON_CONTENT_TYPE: &str = "application/json";
+
+type HttpResponse = Response<Cursor<Vec<u8>>>;
+
+// Plain HTTP endpoints for monitoring and point in time queries, served alongside the WebSocket broadcast server
+// Pairs are written into paths as BASE/QUOTE, for example /book/bitfinex/XRP/BTC
+pub struct Server {
+    books: BookStore,
+    trades: TradeStore,
+    status: StatusStore,
+    metrics: Metrics,
+    clients: Clients,
+    health: Health
+}
+
+impl Server {
+    pub fn run(addr: SocketAddr, books: BookStore, trades: TradeStore, status: StatusStore,
+               metrics: Metrics, clients: Clients, health: Health) {
+        let listener = tiny_http::Server::http(addr).expect("Could not create HTTP server!");
+        info!("Listening for HTTP requests on {}", addr);
+
+        let server = Self { books, trades, status, metrics, clients, health };
+
+        thread::spawn(move || {
+            for request in listener.incoming_requests() {
+                server.handle(request);
+            }
+            info!("HTTP listen socket closed");
+        });
+    }
+
+    fn handle(&self, request: Request) {
+        debug!("Got HTTP request: {} {}", request.method(), request.url());
+
+        let response = {
+            let mut url = request.url().splitn(2, '?');
+            let path = url.next().unwrap_or("");
+            let query = url.next().unwrap_or("");
+            let segments: Vec<&str> = path.split('/').filter(|segment| !segment.is_empty()).collect();
+
+            let routed = match (request.method(), segments.as_slice()) {
+                (&Method::Get, &["metrics"]) =>
+                    Ok(text(200, self.metrics.render(&self.clients.queue_depths()), PROMETHEUS_CONTENT_TYPE)),
+                // Liveness only depends on the broadcast server, since exchanges reconnect by themselves
+                (&Method::Get, &["healthz"]) => {
+                    let report = self.health.report();
+                    Ok(json(if report.listening { 200 } else { 503 }, &report))
+                },
+                (&Method::Get, &["readyz"]) => {
+                    let report = self.health.report();
+                    Ok(json(if report.ready { 200 } else { 503 }, &report))
+                },
+                (&Method::Get, &["exchanges"]) => Ok(self.exchanges()),
+                (&Method::Get, &["book", "consolidated", base, quote]) => self.consolidated_book(base, quote, query),
+                (&Method::Get, &["book", exchange, base, quote]) => self.book(exchange, base, quote, query),
+                (&Method::Get, &["trades", exchange, base, quote]) => self.trades(exchange, base, quote, query),
+                (&Method::Get, _) => Err((404, format!("No such endpoint: {}", path))),
+                (method, _) => Err((405, format!("Method not allowed: {}", method)))
+            };
+
+            routed.unwrap_or_else(|(status, message)| json(status, &Broadcast::Error { message }))
+        };
+
+        if let Err(e) = request.respond(response) {
+            warn!("Could not respond to HTTP request: {}", e);
+        }
+    }
+
+    fn exchanges(&self) -> HttpResponse {
+        let books = self.books.keys();
+        let exchanges = self.status.all().into_iter()
+            .map(|(exchange, state, failures)| {
+                let mut pairs: Vec<CurrencyPair> = books.iter()
+                    .filter(|&&(book_exchange, _)| book_exchange == exchange)
+                    .map(|(_, pair)| pair.clone())
+                    .collect();
+                pairs.sort_unstable();
+                ExchangeSummary { exchange, state, failures, books: pairs }
+            })
+            .collect();
+
+        scaled(200, &Exchanges { exchanges })
+    }
+
+    fn book(&self, exchange: &str, base: &str, quote: &str, query: &str) -> Result<HttpResponse, (u16, String)> {
+        let exchange = parse_exchange(exchange)?;
+        let pair = CurrencyPair::new(base, quote);
+        let depth = parse_query(query, "depth")?.unwrap_or(usize::MAX);
+
+        match self.books.book(exchange, &pair) {
+            Some(book) => {
+                let (bids, asks) = book.top(depth);
+                Ok(scaled(200, &Broadcast::OrderbookSnapshot { source: exchange, pair, bids, asks }))
+            },
+            None => Err((404, format!("No {} orderbook is available for {}", exchange, pair)))
+        }
+    }
+
+    fn consolidated_book(&self, base: &str, quote: &str, query: &str) -> Result<HttpResponse, (u16, String)> {
+        let pair = CurrencyPair::new(base, quote);
+        let depth = parse_query(query, "depth")?.unwrap_or(usize::MAX);
+
+        if !self.books.keys().iter().any(|(_, book_pair)| *book_pair == pair) {
+            return Err((404, format!("No orderbook is available for {}", pair)));
+        }
+
+        let (mut bids, mut asks) = self.books.consolidated(&pair);
+        bids.truncate(depth);
+        asks.truncate(depth);
+        Ok(scaled(200, &Broadcast::ConsolidatedOrderbookSnapshot { pair, bids, asks }))
+    }
+
+    // Trades at or after the since timestamp, in milliseconds
+    fn trades(&self, exchange: &str, base: &str, quote: &str, query: &str) -> Result<HttpResponse, (u16, String)> {
+        let exchange = parse_exchange(exchange)?;
+        let pair = CurrencyPair::new(base, quote);
+        let since = parse_query(query, "since")?.unwrap_or(i64::MIN);
+
+        if !self.trades.keys().contains(&(exchange, pair.clone())) {
+            return Err((404, format!("No {} trades are available for {}", exchange, pair)));
+        }
+
+        let trades = self.trades.recent(exchange, &pair).into_iter()
+            .filter(|&(ts, _, _, _)| ts >= since)
+            .collect();
+        Ok(scaled(200, &Broadcast::TradeSnapshot { source: exchange, pair, trades }))
+    }
+}
+
+fn parse_exchange(value: &str) -> Result<Exchange, (u16, String)> {
+    Exchange::map(value).ok_or_else(|| (404, format!("Unknown exchange {}, expected one of bitfinex, btcmarkets or poloniex", value)))
+}
+
+// A parameter given more than once takes its last value
+fn parse_query<T: FromStr>(query: &str, name: &str) -> Result<Option<T>, (u16, String)> {
+    let value = query.split('&')
+        .filter_map(|param| {
+            let mut param = param.splitn(2, '=');
+            match (param.next(), param.next()) {
+                (Some(key), Some(value)) if key == name => Some(value),
+                _ => None
+            }
+        })
+        .next_back();
+
+    match value {
+        Some(value) => value.parse().map(Some).map_err(|_| (400, format!("Invalid {}: {}", name, value))),
+        None => Ok(None)
+    }
+}
+
+fn scaled<T: ::serde::Serialize>(status: u16, body: &T) -> HttpResponse {
+    json(status, &Scaled { body, multiplier: ::MULTIPLIER })
+}
+
+fn json<T: ::serde::Serialize>(status: u16, body: &T) -> HttpResponse {
+    match ::serde_json::to_string(body) {
+        Ok(serialized) => text(status, serialized, JSON_CONTENT_TYPE),
+        Err(e) => text(500, format!("Could not serialize response: {}", e), "text/plain")
+    }
+}
+
+fn text(status: u16, body: String, content_type: &str) -> HttpResponse {
+    let header = Header::from_bytes(&b"Content-Type"[..], content_type.as_bytes())
+        .expect("Could not build content type header - this should never happen!");
+    Response::from_string(body).with_status_code(status).with_header(header)
+}
\ No newline at end of file
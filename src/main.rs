@@ -5,22 +5,38 @@ extern crate serde;
 extern crate serde_json;
 extern crate simplelog;
 #[macro_use] extern crate log;
-#[macro_use] extern crate dotenv_codegen;
 extern crate dotenv;
 #[macro_use] extern crate error_chain;
+extern crate clap;
+extern crate toml;
+extern crate crc;
+extern crate rand;
+extern crate tiny_http;
+extern crate flate2;
+extern crate rmp_serde;
+extern crate serde_cbor;
+extern crate prost;
+// Referred to by the generated protobuf types
+extern crate core;
 
 mod domain;
+mod config;
 mod broadcast_api;
+mod http_api;
+mod metrics;
+mod recorder;
+mod replay;
 #[macro_use]
 mod consumer;
 
 mod btcmarkets;
 mod bitfinex;
+mod poloniex;
 
 use dotenv::dotenv;
 use simplelog::*;
 use std::fs::File;
-use std::{thread, time};
+use std::{process, thread, time};
 
 const MULTIPLIER: i32 = 100_000_000;
 
@@ -28,14 +44,86 @@ fn main() {
 
     dotenv().ok();
 
-    init_logger(dotenv!("LOG_FILE_PATH"));
+    let config = match config::Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            let causes: Vec<String> = e.iter().map(|cause| cause.to_string()).collect();
+            eprintln!("Invalid configuration: {}", causes.join(": "));
+            process::exit(1);
+        }
+    };
+
+    init_logger(&config.log_file_path, config.log_level);
+    for warning in &config.warnings {
+        warn!("{}", warning);
+    }
+
+    let state = domain::MarketState::new();
+    let status = domain::StatusStore::new();
+    let subscriptions = domain::SubscriptionStore::new();
+    let metrics = metrics::Metrics::new();
+    // Replayed frames are already recorded
+    let recorder_config = match config.replay {
+        Some(_) if config.recorder.is_some() => {
+            warn!("Not recording exchange messages while replaying");
+            None
+        },
+        _ => config.recorder.clone()
+    };
+    let recorder = match recorder::Recorder::start(recorder_config, metrics.clone()) {
+        Ok(recorder) => recorder,
+        Err(e) => {
+            error!("Could not start recording exchange messages: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let server = broadcast_api::server::Server::run(
+        config.server_addr, config.compression, config.slow_clients, state.clone(), status.clone(), metrics.clone());
+
+    let mut health = http_api::health::Health {
+        listening: server.listening(),
+        status: status.clone(),
+        subscriptions: subscriptions.clone(),
+        metrics: metrics.clone(),
+        exchanges: vec!(),
+        pairs: config.pairs.clone(),
+        stale_after: config.reconnect.stale_after
+    };
 
-    let pairs = domain::CurrencyPair::parse(dotenv!("CURRENCY_PAIRS"));
+    let context = consumer::handler::HandlerContext {
+        broadcast_tx: server.tx(),
+        status,
+        subscriptions,
+        metrics: metrics.clone(),
+        recorder,
+        reconnect: config.reconnect.clone(),
+        clock: consumer::Clock::new()
+    };
+
+    if let Some(ref exchange) = config.bitfinex {
+        if config.replay.is_none() {
+            consumer::connect::<bitfinex::BitfinexFactory>(context.clone(), config.pairs.clone(), exchange);
+        }
+        health.exchanges.push((domain::Exchange::Bitfinex, exchange.required));
+    }
+    if let Some(ref exchange) = config.btcmarkets {
+        if config.replay.is_none() {
+            consumer::connect::<btcmarkets::BtcmarketsFactory>(context.clone(), config.pairs.clone(), exchange);
+        }
+        health.exchanges.push((domain::Exchange::BtcMarkets, exchange.required));
+    }
+    if let Some(ref exchange) = config.poloniex {
+        if config.replay.is_none() {
+            consumer::connect::<poloniex::PoloniexFactory>(context.clone(), config.pairs.clone(), exchange);
+        }
+        health.exchanges.push((domain::Exchange::Poloniex, exchange.required));
+    }
 
-    let server = broadcast_api::server::Server::run();
+    replay::start(context.clone(), &config);
 
-    consumer::connect::<bitfinex::BitfinexFactory>(server.tx(), pairs.clone());
-    consumer::connect::<btcmarkets::BtcmarketsFactory>(server.tx(), pairs.clone());
+    http_api::server::Server::run(config.http_addr, state.books, state.trades, context.status.clone(),
+                                  metrics, server.clients(), health);
 
     loop {
         thread::sleep(time::Duration::from_secs(1));
@@ -43,17 +131,17 @@ fn main() {
     }
 }
 
-fn init_logger(path: &str) {
+fn init_logger(path: &str, level: LevelFilter) {
     let mut loggers: Vec<Box<SharedLogger>> = vec!();
     match File::create(path) {
-        Ok(f) => loggers.push(WriteLogger::new(LevelFilter::Debug, Config::default(), f)),
+        Ok(f) => loggers.push(WriteLogger::new(level, Config::default(), f)),
         Err(e) => println!("Could not create log file at {}: {}", path, e)
     }
-    match TermLogger::new(LevelFilter::Debug, Config::default()) {
+    match TermLogger::new(level, Config::default()) {
         Some(logger) => loggers.push(logger),
         None => {
             println!("Could not create terminal logger: falling back to simple logger");
-            loggers.push(SimpleLogger::new(LevelFilter::Debug, Config::default()));
+            loggers.push(SimpleLogger::new(level, Config::default()));
         }
     }
     if let Err(e) = CombinedLogger::init(loggers) {
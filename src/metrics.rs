@@ -0,0 +1,163 @@
+use domain::Exchange;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Default)]
+struct ExchangeMetrics {
+    // Every message received, including heartbeats and messages that could not be handled
+    messages: u64,
+    deserialize_failures: u64,
+    handler_errors: u64,
+    reconnects: u64,
+    broadcast_failures: u64,
+    // Messages that were not recorded because the recorder had fallen behind
+    capture_drops: u64,
+    last_message: Option<Instant>
+}
+
+// Messages to clients that negotiated permessage-deflate, before and after compression
+#[derive(Default)]
+struct CompressionMetrics {
+    messages: u64,
+    raw_bytes: u64,
+    compressed_bytes: u64
+}
+
+// Counters for the health of each exchange feed, shared by every connection to every exchange
+// Along with how well messages to clients compress
+#[derive(Clone, Default)]
+pub struct Metrics {
+    exchanges: Arc<Mutex<HashMap<Exchange, ExchangeMetrics>>>,
+    compression: Arc<Mutex<CompressionMetrics>>
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn message_received(&self, exchange: Exchange) {
+        self.update(exchange, |metrics| {
+            metrics.messages += 1;
+            metrics.last_message = Some(Instant::now());
+        });
+    }
+
+    pub fn deserialize_failed(&self, exchange: Exchange) {
+        self.update(exchange, |metrics| metrics.deserialize_failures += 1);
+    }
+
+    pub fn handler_failed(&self, exchange: Exchange) {
+        self.update(exchange, |metrics| metrics.handler_errors += 1);
+    }
+
+    pub fn reconnecting(&self, exchange: Exchange) {
+        self.update(exchange, |metrics| metrics.reconnects += 1);
+    }
+
+    pub fn broadcasts_failed(&self, exchange: Exchange, count: usize) {
+        self.update(exchange, |metrics| metrics.broadcast_failures += count as u64);
+    }
+
+    pub fn capture_dropped(&self, exchange: Exchange) {
+        self.update(exchange, |metrics| metrics.capture_drops += 1);
+    }
+
+    pub fn message_compressed(&self, raw: usize, compressed: usize) {
+        let mut metrics = self.compression.lock().expect("Metrics lock was poisoned");
+        metrics.messages += 1;
+        metrics.raw_bytes += raw as u64;
+        metrics.compressed_bytes += compressed as u64;
+    }
+
+    pub fn last_message_age(&self, exchange: Exchange) -> Option<Duration> {
+        self.exchanges.lock().expect("Metrics lock was poisoned")
+            .get(&exchange)
+            .and_then(|metrics| metrics.last_message)
+            .map(|last| last.elapsed())
+    }
+
+    fn update<F: FnOnce(&mut ExchangeMetrics)>(&self, exchange: Exchange, f: F) {
+        let mut exchanges = self.exchanges.lock().expect("Metrics lock was poisoned");
+        f(exchanges.entry(exchange).or_default());
+    }
+
+    // Prometheus text exposition of the exchange metrics along with the queue depth of each connected client
+    // https://prometheus.io/docs/instrumenting/exposition_formats/
+    pub fn render(&self, client_queues: &[(u32, usize)]) -> String {
+        let exchanges = self.exchanges.lock().expect("Metrics lock was poisoned");
+        let mut sorted: Vec<(&Exchange, &ExchangeMetrics)> = exchanges.iter().collect();
+        sorted.sort_unstable_by_key(|&(exchange, _)| *exchange);
+
+        let mut out = String::new();
+
+        {
+            let mut exchange_metric = |name: &str, kind: &str, help: &str, value: fn(&ExchangeMetrics) -> Option<String>| {
+                header(&mut out, name, kind, help);
+                for &(exchange, metrics) in &sorted {
+                    if let Some(value) = value(metrics) {
+                        let _ = writeln!(out, "{}{{exchange=\"{}\"}} {}", name, exchange.to_string().to_lowercase(), value);
+                    }
+                }
+            };
+
+            exchange_metric("aggregator_exchange_messages_total", "counter",
+                "Messages received from the exchange, including heartbeats",
+                |metrics| Some(metrics.messages.to_string()));
+            exchange_metric("aggregator_exchange_deserialize_failures_total", "counter",
+                "Messages from the exchange that could not be deserialized",
+                |metrics| Some(metrics.deserialize_failures.to_string()));
+            exchange_metric("aggregator_exchange_handler_errors_total", "counter",
+                "Messages from the exchange that were deserialized but could not be handled",
+                |metrics| Some(metrics.handler_errors.to_string()));
+            exchange_metric("aggregator_exchange_reconnects_total", "counter",
+                "Reconnects to the exchange after its connection was lost or could not be made",
+                |metrics| Some(metrics.reconnects.to_string()));
+            exchange_metric("aggregator_exchange_broadcast_failures_total", "counter",
+                "Broadcasts from the exchange handler that could not be passed on to the server",
+                |metrics| Some(metrics.broadcast_failures.to_string()));
+            exchange_metric("aggregator_exchange_capture_drops_total", "counter",
+                "Messages from the exchange that were not recorded because the recorder had fallen behind",
+                |metrics| Some(metrics.capture_drops.to_string()));
+            exchange_metric("aggregator_exchange_last_message_age_seconds", "gauge",
+                "Seconds since anything was last received from the exchange",
+                |metrics| metrics.last_message.map(|last| {
+                    let age = last.elapsed();
+                    format!("{:.3}", age.as_secs() as f64 + f64::from(age.subsec_millis()) / 1000.0)
+                }));
+        }
+
+        {
+            let compression = self.compression.lock().expect("Metrics lock was poisoned");
+
+            header(&mut out, "aggregator_compressed_messages_total", "counter",
+                "Messages compressed for clients using permessage-deflate");
+            let _ = writeln!(out, "aggregator_compressed_messages_total {}", compression.messages);
+            header(&mut out, "aggregator_compression_raw_bytes_total", "counter",
+                "Bytes of messages to clients using permessage-deflate before compression");
+            let _ = writeln!(out, "aggregator_compression_raw_bytes_total {}", compression.raw_bytes);
+            header(&mut out, "aggregator_compression_compressed_bytes_total", "counter",
+                "Bytes of messages to clients using permessage-deflate after compression");
+            let _ = writeln!(out, "aggregator_compression_compressed_bytes_total {}", compression.compressed_bytes);
+        }
+
+        header(&mut out, "aggregator_connected_clients", "gauge", "Clients connected to the broadcast server");
+        let _ = writeln!(out, "aggregator_connected_clients {}", client_queues.len());
+
+        header(&mut out, "aggregator_client_queue_depth", "gauge",
+               "Messages sent to each client that it has not yet confirmed receiving, including any still being written. \
+               Clients confirm with a pong every quarter of the slow client limit, so this can overstate the backlog by that much");
+        for &(client, depth) in client_queues {
+            let _ = writeln!(out, "aggregator_client_queue_depth{{client=\"{}\"}} {}", client, depth);
+        }
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
\ No newline at end of file
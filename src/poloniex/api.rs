@@ -0,0 +1,49 @@
+use std::collections::HashMap;
+
+pub type ChannelId = i32;
+pub type SequenceNumber = i64;
+pub type Timestamp = i64;
+pub type Side = i32; // 1 for bids/buys, 0 for asks/sells
+
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum Request {
+    Subscribe {
+        command: String,
+        channel: String
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Response {
+    // Neither is read, they only have to match for the message to be recognised
+    #[allow(dead_code)]
+    Heartbeat([ChannelId; 1]), // The channel ID here is always 1010
+    #[allow(dead_code)]
+    Acknowledgement(ChannelId, i32),
+    Update(ChannelId, SequenceNumber, Vec<Event>),
+    Error {
+        error: String
+    }
+}
+
+// Events are told apart by their shape, so the event type is never read
+#[allow(dead_code)]
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Event {
+    // The leading string of each event is the event type - "i", "o" or "t" respectively
+    InitialOrderbook(String, InitialOrderbook),
+    OrderbookChange(String, Side, String, String),
+    Trade(String, String, Side, String, String, Timestamp)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct InitialOrderbook {
+    #[serde(rename = "currencyPair")]
+    pub currency_pair: String,
+    // Asks first, then bids - each a map of price to amount
+    #[serde(rename = "orderBook")]
+    pub orderbook: (HashMap<String, String>, HashMap<String, String>)
+}
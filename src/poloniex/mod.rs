@@ -0,0 +1,394 @@
+mod api;
+
+use self::api::*;
+use broadcast_api::{Broadcast, BroadcastType, Price, Volume};
+use super::domain::*;
+use config::ExchangeConfig;
+use consumer::{self, error::*, handler::{HandlerCore, HandlerContext}, MarketHandler, ConnectionFactory};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use ws;
+
+type Levels = Vec<(Price, Volume)>;
+
+// Poloniex uses its own codes for some assets
+const ASSET_CODES: &AssetCodes = &[
+    ("XLM", "STR")
+];
+
+pub struct PoloniexHandler {
+    inner: HandlerCore,
+    channels: HashMap<ChannelId, CurrencyPair>,
+    symbols: SymbolTable,
+    // Last sequence number seen on each channel - a channel is only present once its orderbook is initialised
+    sequences: HashMap<ChannelId, SequenceNumber>,
+    pairs: Vec<CurrencyPair>
+}
+
+impl ws::Handler for PoloniexHandler {
+
+    generic_open!(Exchange::Poloniex);
+
+    generic_on_message!(Response);
+
+    generic_on_close!(Exchange::Poloniex);
+
+    generic_on_timeout!(Exchange::Poloniex);
+}
+
+impl MarketHandler for PoloniexHandler {
+
+    fn get_requests(&self) -> Vec<String> {
+        self.pairs.iter().map(|pair| subscribe_request(&Self::stringify_pair(pair)))
+            .map(|req| ::serde_json::to_string(&req).unwrap()).collect()
+    }
+
+    // Poloniex lists the quote currency first, for example BTC_XRP
+    fn stringify_pair(pair: &CurrencyPair) -> String {
+        format!("{}_{}", exchange_asset_code(&pair.quote, ASSET_CODES), exchange_asset_code(&pair.base, ASSET_CODES))
+    }
+}
+
+pub struct PoloniexFactory {
+    context: HandlerContext,
+    pairs: Vec<CurrencyPair>
+}
+
+impl ConnectionFactory for PoloniexFactory {
+    const EXCHANGE: Exchange = Exchange::Poloniex;
+
+    fn new(context: HandlerContext, pairs: Vec<CurrencyPair>, _exchange: &ExchangeConfig) -> Self {
+        Self { context, pairs }
+    }
+
+    fn handler(&self, inner: HandlerCore) -> Self::Handler {
+        Self::Handler {
+            inner,
+            symbols: SymbolTable::new(&self.pairs, PoloniexHandler::stringify_pair),
+            pairs: self.pairs.clone(),
+            channels: HashMap::new(),
+            sequences: HashMap::new()
+        }
+    }
+}
+
+impl ws::Factory for PoloniexFactory {
+    type Handler = PoloniexHandler;
+
+    fn connection_made(&mut self, sender: ws::Sender) -> Self::Handler {
+        self.handler(HandlerCore::new(self.context.clone(), sender, Self::EXCHANGE))
+    }
+}
+
+impl PoloniexHandler {
+    fn handle_response(&mut self, response: Response) -> Result<BroadcastType> {
+        match response {
+            Response::Update(channel_id, sequence, events) => {
+                let is_initial = events.iter().any(|event| matches!(*event, Event::InitialOrderbook(..)));
+
+                if is_initial {
+                    self.sequences.insert(channel_id, sequence);
+                } else if !self.check_sequence(channel_id, sequence) {
+                    return Ok(BroadcastType::None);
+                }
+
+                self.map_events(channel_id, events)
+            },
+            Response::Error { error } => {
+                error!("{} responded with an error: {}", Exchange::Poloniex, error);
+                Ok(BroadcastType::None)
+            },
+            _ => Ok(BroadcastType::None)
+        }
+    }
+
+    // Every event on a channel carries the next sequence number for that channel
+    // A gap means we have missed an orderbook change, so the book must be fetched again from scratch
+    fn check_sequence(&mut self, channel_id: ChannelId, sequence: SequenceNumber) -> bool {
+        match self.sequences.get(&channel_id).cloned() {
+            None => {
+                trace!("Discarding message on channel {} until its orderbook is initialised", channel_id);
+                false
+            },
+            Some(last) if sequence <= last => {
+                debug!("Discarding stale message on channel {}: sequence {} after {}", channel_id, sequence, last);
+                false
+            },
+            Some(last) if sequence > last + 1 => {
+                warn!("Sequence gap on {} channel {}: expected {} but got {}",
+                      Exchange::Poloniex, channel_id, last + 1, sequence);
+                self.resubscribe(channel_id);
+                false
+            },
+            Some(_) => {
+                self.sequences.insert(channel_id, sequence);
+                true
+            }
+        }
+    }
+
+    fn resubscribe(&mut self, channel_id: ChannelId) {
+        self.sequences.remove(&channel_id);
+
+        let requests: Vec<String> = match self.channels.get(&channel_id) {
+            Some(pair) => {
+                self.inner.pair_unsubscribed(pair);
+                let channel = Self::stringify_pair(pair);
+                [unsubscribe_request(&channel), subscribe_request(&channel)].iter()
+                    .map(|req| ::serde_json::to_string(req).unwrap()).collect()
+            },
+            None => return
+        };
+
+        info!("Resubscribing to {} channel {}", Exchange::Poloniex, channel_id);
+        if let Err(e) = self.inner.send_upstream(&requests) {
+            error!("Could not resubscribe to {} channel {}: {}", Exchange::Poloniex, channel_id, e);
+        }
+    }
+
+    fn map_events(&mut self, channel_id: ChannelId, events: Vec<Event>) -> Result<BroadcastType> {
+        let mut broadcasts = vec!();
+
+        // Orderbook changes are collected per price level so only the final state of each level is broadcast
+        let (mut bids, mut asks) = (BTreeMap::new(), BTreeMap::new());
+
+        for event in events {
+            if let Event::InitialOrderbook(_, orderbook) = event {
+                let pair = match map_pair_code(&self.symbols, &orderbook.currency_pair) {
+                    Ok(pair) => pair,
+                    Err(e) => {
+                        self.leave_channel(channel_id, &orderbook.currency_pair);
+                        return Err(e);
+                    }
+                };
+                debug!("{} pair code {:?} maps to channel ID {}", Exchange::Poloniex, pair, channel_id);
+                self.channels.insert(channel_id, pair.clone());
+                self.inner.pair_subscribed(&pair);
+                bids.clear();
+                asks.clear();
+                broadcasts.push(map_initial_orderbook(pair, orderbook));
+                continue;
+            }
+
+            let pair = match self.channels.get(&channel_id) {
+                Some(pair) => pair.clone(),
+                None => return Err(ErrorKind::UnknownChannel(Exchange::Poloniex, channel_id as i64).into())
+            };
+
+            match event {
+                Event::OrderbookChange(_, side, price, amount) => {
+                    if let (Some(price), Some(amount)) = (parse_value(&price), parse_value(&amount)) {
+                        if side == 1 {
+                            bids.insert(price, amount);
+                        } else {
+                            asks.insert(price, amount);
+                        }
+                    }
+                },
+                Event::Trade(_, _trade_id, side, price, amount, ts) => {
+                    if let Some(trade) = map_trade(pair, side, &price, &amount, ts) {
+                        broadcasts.push(trade);
+                    }
+                },
+                Event::InitialOrderbook(..) => unreachable!()
+            }
+        }
+
+        if let Some(pair) = self.channels.get(&channel_id) {
+            broadcasts.extend(map_orderbook_changes(pair.clone(), bids, asks));
+        }
+
+        Ok(BroadcastType::Many(broadcasts))
+    }
+
+    // Unsubscribes from a channel that can't be handled, so its sequence is never initialised and its messages are discarded
+    fn leave_channel(&mut self, channel_id: ChannelId, pair_code: &str) {
+        self.sequences.remove(&channel_id);
+
+        let requests = vec!(::serde_json::to_string(&unsubscribe_request(pair_code)).unwrap());
+
+        info!("Leaving {} channel {}", Exchange::Poloniex, pair_code);
+        if let Err(e) = self.inner.send_upstream(&requests) {
+            error!("Could not leave {} channel {}: {}", Exchange::Poloniex, pair_code, e);
+        }
+    }
+}
+
+fn subscribe_request(channel: &str) -> Request {
+    Request::Subscribe {
+        command: "subscribe".to_string(),
+        channel: channel.to_string()
+    }
+}
+
+fn unsubscribe_request(channel: &str) -> Request {
+    Request::Subscribe {
+        command: "unsubscribe".to_string(),
+        channel: channel.to_string()
+    }
+}
+
+// Pairs list: https://poloniex.com/public?command=returnTicker
+fn map_pair_code(symbols: &SymbolTable, pair_code: &str) -> Result<CurrencyPair> {
+    match symbols.pair(pair_code) {
+        Some(pair) => Ok(pair.clone()),
+        None => Err(ErrorKind::UnknownPair(Exchange::Poloniex, pair_code.to_string()).into())
+    }
+}
+
+// Poloniex sends all prices and amounts as strings
+fn parse_value(value: &str) -> Option<i64> {
+    match value.parse::<f64>() {
+        Ok(parsed) => Some(consumer::standardise_value(parsed)),
+        Err(e) => {
+            error!("Could not parse {} value {}: {}", Exchange::Poloniex, value, e);
+            None
+        }
+    }
+}
+
+fn map_initial_orderbook(pair: CurrencyPair, orderbook: InitialOrderbook) -> Broadcast {
+    let (asks_in, bids_in) = orderbook.orderbook;
+
+    let parse_side = |side: HashMap<String, String>| -> Vec<(Price, Volume)> {
+        side.iter()
+            .filter_map(|(price, amount)| match (parse_value(price), parse_value(amount)) {
+                (Some(price), Some(amount)) => Some((price, amount)),
+                _ => None
+            })
+            .collect()
+    };
+
+    let mut bids = parse_side(bids_in);
+    let mut asks = parse_side(asks_in);
+    bids.sort_unstable_by_key(|&(price, _)| Reverse(price));
+    asks.sort_unstable_by_key(|&(price, _)| price);
+
+    Broadcast::OrderbookSnapshot {
+        source: Exchange::Poloniex,
+        pair, bids, asks
+    }
+}
+
+// A change with an amount of zero removes the price level entirely
+fn map_orderbook_changes(pair: CurrencyPair, bids: BTreeMap<Price, Volume>, asks: BTreeMap<Price, Volume>) -> Vec<Broadcast> {
+    let (removed_bids, new_bids): (Levels, Levels) = bids.into_iter().partition(|&(_, amount)| amount == 0);
+    let (removed_asks, new_asks): (Levels, Levels) = asks.into_iter().partition(|&(_, amount)| amount == 0);
+
+    let mut responses = vec!();
+
+    if !removed_bids.is_empty() || !removed_asks.is_empty() {
+        responses.push(Broadcast::OrderbookRemove {
+            source: Exchange::Poloniex,
+            pair: pair.clone(),
+            bids: removed_bids,
+            asks: removed_asks
+        });
+    }
+
+    if !new_bids.is_empty() || !new_asks.is_empty() {
+        responses.push(Broadcast::OrderbookUpdate {
+            source: Exchange::Poloniex,
+            pair,
+            bids: new_bids,
+            asks: new_asks
+        });
+    }
+
+    responses
+}
+
+fn map_trade(pair: CurrencyPair, side: Side, price: &str, amount: &str, ts: Timestamp) -> Option<Broadcast> {
+    let standardised_price = parse_value(price)?;
+    let standardised_amount = parse_value(amount)?;
+
+    // Sells are reported with a negative amount, matching other exchanges
+    let standardised_amount = if side == 1 { standardised_amount } else { -standardised_amount };
+
+    Some(Broadcast::Trade {
+        source: Exchange::Poloniex,
+        pair,
+        trade: (ts * 1000, standardised_price, standardised_amount, standardised_price * standardised_amount)
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use ws::Handler;
+
+    // Frames as Poloniex sends them on the BTC_XRP channel
+    const INITIAL: &str = r#"[148,534814,[["i",{"currencyPair":"BTC_XRP","orderBook":[{"0.00009000":"5.5","0.00009100":"2"},{"0.00008900":"10","0.00008800":"1"}]}]]]"#;
+    const CHANGES: &str = r#"[148,534815,[["o",1,"0.00008900","0.00000000"],["o",0,"0.00009000","7"],["o",1,"0.00008950","3"],["t","42",0,"0.00008900","2",1532000000]]]"#;
+    const AFTER_GAP: &str = r#"[148,534817,[["o",1,"0.00008800","4"]]]"#;
+
+    fn pair() -> CurrencyPair {
+        CurrencyPair::new("XRP", "BTC")
+    }
+
+    fn handler() -> (PoloniexHandler, HandlerContext, mpsc::Receiver<::broadcast_api::Stamped>) {
+        let (context, broadcasts) = HandlerContext::detached();
+        let factory = PoloniexFactory { context: context.clone(), pairs: vec!(pair()) };
+        let inner = HandlerCore::detached(context.clone(), Exchange::Poloniex);
+        (factory.handler(inner), context, broadcasts)
+    }
+
+    fn receive(handler: &mut PoloniexHandler, broadcasts: &mpsc::Receiver<::broadcast_api::Stamped>, frame: &str)
+               -> Vec<Broadcast> {
+        handler.on_message(ws::Message::text(frame)).unwrap();
+        broadcasts.try_iter().map(|(broadcast, _)| broadcast).collect()
+    }
+
+    #[test]
+    fn parses_sample_frames() {
+        match ::serde_json::from_str::<Response>(INITIAL).unwrap() {
+            Response::Update(148, 534814, ref events) => match events.as_slice() {
+                [Event::InitialOrderbook(_, orderbook)] => assert_eq!(orderbook.currency_pair, "BTC_XRP"),
+                other => panic!("Expected an initial orderbook: {:?}", other)
+            },
+            other => panic!("Expected an update: {:?}", other)
+        }
+        assert!(matches!(::serde_json::from_str::<Response>("[1010]").unwrap(), Response::Heartbeat(..)));
+        assert!(matches!(::serde_json::from_str::<Response>("[148,1]").unwrap(), Response::Acknowledgement(148, 1)));
+        assert!(matches!(::serde_json::from_str::<Response>(r#"{"error":"Invalid channel."}"#).unwrap(), Response::Error { .. }));
+    }
+
+    #[test]
+    fn maps_snapshots_changes_and_trades() {
+        let (mut handler, context, broadcasts) = handler();
+
+        let snapshot = receive(&mut handler, &broadcasts, INITIAL);
+        assert_eq!(snapshot, vec!(Broadcast::OrderbookSnapshot {
+            source: Exchange::Poloniex,
+            pair: pair(),
+            bids: vec!((8900, 1_000_000_000), (8800, 100_000_000)),
+            asks: vec!((9000, 550_000_000), (9100, 200_000_000))
+        }));
+        assert!(context.subscriptions.contains(Exchange::Poloniex, &pair()));
+
+        let changes = receive(&mut handler, &broadcasts, CHANGES);
+        assert_eq!(changes, vec!(
+            Broadcast::Trade { source: Exchange::Poloniex, pair: pair(), trade: (1532000000000, 8900, -200_000_000, -1_780_000_000_000) },
+            Broadcast::OrderbookRemove { source: Exchange::Poloniex, pair: pair(), bids: vec!((8900, 0)), asks: vec!() },
+            Broadcast::OrderbookUpdate { source: Exchange::Poloniex, pair: pair(), bids: vec!((8950, 300_000_000)), asks: vec!((9000, 700_000_000)) }
+        ));
+    }
+
+    #[test]
+    fn resubscribes_after_a_sequence_gap() {
+        let (mut handler, context, broadcasts) = handler();
+        receive(&mut handler, &broadcasts, INITIAL);
+
+        // 534816 was missed, so the change is dropped and the channel is subscribed to afresh
+        assert!(receive(&mut handler, &broadcasts, AFTER_GAP).is_empty());
+        assert!(!context.subscriptions.contains(Exchange::Poloniex, &pair()));
+        assert!(!handler.sequences.contains_key(&148));
+
+        // Nothing more is used from the channel until its new snapshot arrives
+        assert!(receive(&mut handler, &broadcasts, CHANGES).is_empty());
+        assert_eq!(receive(&mut handler, &broadcasts, INITIAL).len(), 1);
+        assert!(context.subscriptions.contains(Exchange::Poloniex, &pair()));
+    }
+}
\ No newline at end of file
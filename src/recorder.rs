@@ -0,0 +1,306 @@
+use config::RecorderConfig;
+use domain::Exchange;
+use flate2::{Compression, write::GzEncoder};
+use metrics::Metrics;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, mpsc, atomic::{AtomicBool, Ordering}};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const FILE_PREFIX: &str = "capture-";
+const FILE_SUFFIX: &str = ".jsonl.gz";
+// Frames waiting to be written, beyond which new frames are dropped rather than using ever more memory
+const QUEUE_LEN: usize = 10_000;
+// Captures are flushed this often, or sooner once this many bytes of frames have been written since the last flush
+// Each flush ends a deflate block, so flushing after every frame would make captures much larger
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+const FLUSH_BYTES: usize = 1 << 20;
+
+// A message exactly as it was received from an exchange
+// Connection IDs are unique for the life of the process, so frames from separate connections can be told apart
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CapturedFrame {
+    // Milliseconds since the epoch
+    pub ts: u64,
+    pub exchange: Exchange,
+    pub connection: u64,
+    pub frame: String
+}
+
+// Writes received frames to disk on its own thread, so that exchange handlers are never held up by it
+// Frames are dropped and counted if the disk cannot keep up
+// Does nothing when recording is disabled
+#[derive(Clone, Default)]
+pub struct Recorder {
+    tx: Option<mpsc::SyncSender<CapturedFrame>>,
+    metrics: Metrics,
+    // Set while frames are being dropped, so that it is only logged when it starts
+    dropping: Arc<AtomicBool>
+}
+
+impl Recorder {
+    pub fn start(config: Option<RecorderConfig>, metrics: Metrics) -> io::Result<Self> {
+        let config = match config {
+            Some(config) => config,
+            None => return Ok(Self::default())
+        };
+
+        fs::create_dir_all(&config.dir)?;
+        info!("Recording exchange messages to {}", config.dir.display());
+
+        let (tx, rx) = mpsc::sync_channel(QUEUE_LEN);
+        thread::spawn(move || {
+            let mut writer = CaptureWriter { config, file: None, unflushed: 0 };
+            writer.run(rx);
+        });
+
+        Ok(Self { tx: Some(tx), metrics, dropping: Arc::new(AtomicBool::new(false)) })
+    }
+
+    pub fn record(&self, exchange: Exchange, connection: u64, frame: &str) {
+        if let Some(ref tx) = self.tx {
+            let captured = CapturedFrame { ts: now_millis(), exchange, connection, frame: frame.to_string() };
+            match tx.try_send(captured) {
+                Ok(_) => self.dropping.store(false, Ordering::Relaxed),
+                Err(mpsc::TrySendError::Full(_)) => {
+                    self.metrics.capture_dropped(exchange);
+                    if !self.dropping.swap(true, Ordering::Relaxed) {
+                        warn!("Recorder has fallen {} frames behind, dropping frames until it catches up", QUEUE_LEN);
+                    }
+                },
+                Err(mpsc::TrySendError::Disconnected(_)) => error!("Could not pass frame to the recorder, it has stopped")
+            }
+        }
+    }
+}
+
+struct CaptureWriter {
+    config: RecorderConfig,
+    // The open capture file and when it was started
+    file: Option<(GzEncoder<File>, Instant)>,
+    // Bytes of frames written since the capture was last flushed
+    unflushed: usize
+}
+
+impl CaptureWriter {
+    // Frames are flushed regularly, so a capture can be read while it is still being written
+    fn run(&mut self, rx: mpsc::Receiver<CapturedFrame>) {
+        self.remove_expired();
+
+        let mut last_flush = Instant::now();
+        loop {
+            let wait = FLUSH_INTERVAL.checked_sub(last_flush.elapsed()).unwrap_or_default();
+            match rx.recv_timeout(wait) {
+                Ok(frame) => self.write(&frame),
+                Err(mpsc::RecvTimeoutError::Timeout) => (),
+                Err(mpsc::RecvTimeoutError::Disconnected) => break
+            }
+
+            if self.unflushed >= FLUSH_BYTES || last_flush.elapsed() >= FLUSH_INTERVAL {
+                self.flush();
+                last_flush = Instant::now();
+            }
+        }
+
+        self.finish();
+    }
+
+    fn write(&mut self, frame: &CapturedFrame) {
+        let expired = self.file.as_ref().is_some_and(|&(_, started)| started.elapsed() >= self.config.rotate);
+        if expired {
+            self.finish();
+            self.remove_expired();
+        }
+
+        if self.file.is_none() {
+            let path = self.config.dir.join(format!("{}{}{}", FILE_PREFIX, frame.ts, FILE_SUFFIX));
+            match File::create(&path) {
+                Ok(file) => {
+                    debug!("Started capture file {}", path.display());
+                    self.file = Some((GzEncoder::new(file, Compression::default()), Instant::now()));
+                },
+                Err(e) => {
+                    error!("Could not create capture file {}: {}", path.display(), e);
+                    return;
+                }
+            }
+        }
+
+        if let Some((ref mut file, _)) = self.file {
+            let written = ::serde_json::to_writer(&mut *file, frame).map_err(io::Error::from)
+                .and_then(|_| file.write_all(b"\n"));
+            if let Err(e) = written {
+                error!("Could not write to capture file: {}", e);
+            }
+            self.unflushed += frame.frame.len();
+        }
+    }
+
+    fn flush(&mut self) {
+        if self.unflushed == 0 {
+            return;
+        }
+        self.unflushed = 0;
+        if let Some((ref mut file, _)) = self.file {
+            if let Err(e) = file.flush() {
+                error!("Could not flush capture file: {}", e);
+            }
+        }
+    }
+
+    // Completes the gzip stream of the open file
+    fn finish(&mut self) {
+        self.unflushed = 0;
+        if let Some((file, _)) = self.file.take() {
+            if let Err(e) = file.finish() {
+                error!("Could not finish capture file: {}", e);
+            }
+        }
+    }
+
+    fn remove_expired(&self) {
+        let entries = match fs::read_dir(&self.config.dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                error!("Could not list capture files in {}: {}", self.config.dir.display(), e);
+                return;
+            }
+        };
+
+        let expired: Vec<PathBuf> = entries.filter_map(|entry| entry.ok())
+            .filter(|entry| is_capture_file(&entry.path()))
+            .filter(|entry| entry.metadata().and_then(|metadata| metadata.modified()).ok()
+                .and_then(|modified| modified.elapsed().ok())
+                .is_some_and(|age| age > self.config.retention))
+            .map(|entry| entry.path())
+            .collect();
+
+        for path in expired {
+            match fs::remove_file(&path) {
+                Ok(_) => info!("Removed expired capture file {}", path.display()),
+                Err(e) => error!("Could not remove expired capture file {}: {}", path.display(), e)
+            }
+        }
+    }
+}
+
+// Capture files sort by name in the order they were written
+pub fn is_capture_file(path: &Path) -> bool {
+    path.file_name().and_then(|name| name.to_str())
+        .is_some_and(|name| name.starts_with(FILE_PREFIX) && name.ends_with(FILE_SUFFIX))
+}
+
+fn now_millis() -> u64 {
+    let time = SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards");
+    ::consumer::millis(time)
+}
+
+// Writes frames to capture files just as the recorder's thread does, for tests that need a capture to read
+#[cfg(test)]
+pub fn write_capture(config: RecorderConfig, frames: Vec<CapturedFrame>) {
+    let (tx, rx) = mpsc::sync_channel(frames.len());
+    for frame in frames {
+        tx.send(frame).expect("Capture queue has room for every frame");
+    }
+    drop(tx);
+    CaptureWriter { config, file: None, unflushed: 0 }.run(rx);
+}
+
+// An empty directory of its own for each test
+#[cfg(test)]
+pub fn test_dir(name: &str) -> PathBuf {
+    let dir = ::std::env::temp_dir().join(format!("market-aggregator-{}-{}", name, ::std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).expect("Test directory can be created");
+    dir
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::MultiGzDecoder;
+    use std::io::{BufRead, BufReader};
+
+    const HOUR: Duration = Duration::from_secs(3600);
+
+    fn config(dir: &Path, rotate: Duration) -> RecorderConfig {
+        RecorderConfig { dir: dir.to_path_buf(), rotate, retention: HOUR }
+    }
+
+    fn frame(ts: u64, connection: u64, frame: &str) -> CapturedFrame {
+        CapturedFrame { ts, exchange: Exchange::Poloniex, connection, frame: frame.to_string() }
+    }
+
+    fn capture_files(dir: &Path) -> Vec<PathBuf> {
+        let mut files: Vec<PathBuf> = fs::read_dir(dir).unwrap().map(|entry| entry.unwrap().path()).collect();
+        files.sort_unstable();
+        files
+    }
+
+    fn read(path: &Path) -> Vec<(u64, Exchange, u64, String)> {
+        BufReader::new(MultiGzDecoder::new(File::open(path).unwrap())).lines()
+            .map(|line| ::serde_json::from_str::<CapturedFrame>(&line.unwrap()).unwrap())
+            .map(|frame| (frame.ts, frame.exchange, frame.connection, frame.frame))
+            .collect()
+    }
+
+    #[test]
+    fn reads_back_what_was_written() {
+        let dir = test_dir("recorder-round-trip");
+        let frames = vec!(frame(1_600_000_000_000, 1, "[1010]"), frame(1_600_000_000_500, 1, r#"[148,1,[["o",1,"0.1","2"]]]"#));
+        write_capture(config(&dir, HOUR), frames);
+
+        let files = capture_files(&dir);
+        assert_eq!(files, vec!(dir.join("capture-1600000000000.jsonl.gz")));
+        assert_eq!(read(&files[0]), vec!(
+            (1_600_000_000_000, Exchange::Poloniex, 1, "[1010]".to_string()),
+            (1_600_000_000_500, Exchange::Poloniex, 1, r#"[148,1,[["o",1,"0.1","2"]]]"#.to_string())));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rotates_to_files_that_sort_in_the_order_written() {
+        let dir = test_dir("recorder-rotate");
+        let frames = (0..3).map(|i| frame(1_600_000_000_000 + i * 999, i, &format!("[{}]", i))).collect();
+        write_capture(config(&dir, Duration::from_secs(0)), frames);
+
+        let files = capture_files(&dir);
+        assert_eq!(files.len(), 3);
+        for (i, file) in files.iter().enumerate() {
+            assert!(is_capture_file(file));
+            assert_eq!(read(file), vec!((1_600_000_000_000 + i as u64 * 999, Exchange::Poloniex, i as u64, format!("[{}]", i))));
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn recognises_capture_files() {
+        assert!(is_capture_file(Path::new("captures/capture-1600000000000.jsonl.gz")));
+        assert!(!is_capture_file(Path::new("captures/capture-1600000000000.jsonl")));
+        assert!(!is_capture_file(Path::new("captures/1600000000000.jsonl.gz")));
+        assert!(!is_capture_file(Path::new("captures/capture-1600000000000.jsonl.gz.partial")));
+        assert!(!is_capture_file(Path::new("captures")));
+    }
+
+    #[test]
+    fn removes_expired_capture_files() {
+        let dir = test_dir("recorder-retention");
+        let (expired, current, other) = (dir.join("capture-1.jsonl.gz"), dir.join("capture-2.jsonl.gz"), dir.join("notes.txt"));
+        for path in &[&expired, &current, &other] {
+            File::create(path).unwrap();
+        }
+        let long_ago = SystemTime::now() - HOUR * 2;
+        for path in &[&expired, &other] {
+            File::options().write(true).open(path).unwrap().set_modified(long_ago).unwrap();
+        }
+
+        CaptureWriter { config: config(&dir, HOUR), file: None, unflushed: 0 }.remove_expired();
+        assert_eq!(capture_files(&dir), vec!(current, other));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
\ No newline at end of file
@@ -0,0 +1,285 @@
+use bitfinex::BitfinexFactory;
+use broadcast_api::Timestamp;
+use btcmarkets::BtcmarketsFactory;
+use config::{Config, ExchangeConfig, ReplayConfig};
+use consumer::{Clock, ConnectionFactory};
+use consumer::handler::{HandlerContext, HandlerCore};
+use domain::{CurrencyPair, Exchange};
+use flate2::read::MultiGzDecoder;
+use poloniex::PoloniexFactory;
+use recorder::{self, CapturedFrame};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+use ws;
+
+// Builds the handler for a recorded connection, hiding which exchange it is for
+type HandlerBuilder = Box<dyn Fn(HandlerCore) -> Box<dyn ws::Handler>>;
+
+// Feeds captured frames through the same handlers that received them, so that whatever they broadcast
+// is served exactly as the aggregator would have served it at the time
+// Pacing follows the recorded timestamps, scaled by the replay speed, and broadcasts are stamped with them
+pub fn start(context: HandlerContext, config: &Config) {
+    let replay = match config.replay {
+        Some(ref replay) => replay.clone(),
+        None => return
+    };
+    let context = HandlerContext { clock: Clock::replayed(), ..context };
+    let pairs = config.pairs.clone();
+    let (bitfinex, btcmarkets, poloniex) = (config.bitfinex.clone(), config.btcmarkets.clone(), config.poloniex.clone());
+
+    thread::spawn(move || {
+        let mut builders = HashMap::new();
+        if let Some(ref exchange) = bitfinex {
+            builders.insert(Exchange::Bitfinex, builder::<BitfinexFactory>(context.clone(), pairs.clone(), exchange));
+        }
+        if let Some(ref exchange) = btcmarkets {
+            builders.insert(Exchange::BtcMarkets, builder::<BtcmarketsFactory>(context.clone(), pairs.clone(), exchange));
+        }
+        if let Some(ref exchange) = poloniex {
+            builders.insert(Exchange::Poloniex, builder::<PoloniexFactory>(context.clone(), pairs.clone(), exchange));
+        }
+
+        let files = match capture_files(&replay.path) {
+            Ok(files) => files,
+            Err(e) => {
+                error!("Could not find capture files to replay in {}: {}", replay.path.display(), e);
+                return;
+            }
+        };
+
+        let mut replayer = Replayer {
+            context,
+            config: replay,
+            builders,
+            connections: HashMap::new(),
+            skipped: HashSet::new(),
+            started: None,
+            frames: 0
+        };
+        for path in files {
+            replayer.replay_file(&path);
+        }
+
+        // Handlers are left open so that the state they built up can still be queried
+        info!("Finished replaying {} frames", replayer.frames);
+    });
+}
+
+fn builder<T: ConnectionFactory + 'static>(context: HandlerContext, pairs: Vec<CurrencyPair>, exchange: &ExchangeConfig) -> HandlerBuilder
+    where T::Handler: 'static {
+    let factory = T::new(context, pairs, exchange);
+    Box::new(move |inner| Box::new(factory.handler(inner)))
+}
+
+// A single capture file, or every capture file in a directory in the order they were written
+fn capture_files(path: &Path) -> io::Result<Vec<PathBuf>> {
+    if !path.is_dir() {
+        return Ok(vec!(path.to_path_buf()));
+    }
+
+    let mut files: Vec<PathBuf> = fs::read_dir(path)?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| recorder::is_capture_file(path))
+        .collect();
+    files.sort_unstable();
+    Ok(files)
+}
+
+struct Replayer {
+    context: HandlerContext,
+    config: ReplayConfig,
+    builders: HashMap<Exchange, HandlerBuilder>,
+    // The recorded connection being replayed for each exchange, along with its handler
+    connections: HashMap<Exchange, (u64, Box<dyn ws::Handler>)>,
+    // Exchanges with frames in the capture that are disabled, which are only warned about once
+    skipped: HashSet<Exchange>,
+    // Timestamp of the first frame and when it was replayed
+    started: Option<(u64, Instant)>,
+    frames: u64
+}
+
+impl Replayer {
+    fn replay_file(&mut self, path: &Path) {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) => {
+                error!("Could not open capture file {}: {}", path.display(), e);
+                return;
+            }
+        };
+        info!("Replaying capture file {}", path.display());
+
+        for (number, line) in BufReader::new(MultiGzDecoder::new(file)).lines().enumerate() {
+            let line = match line {
+                Ok(line) => line,
+                Err(e) => {
+                    // Files that were still being written to end part way through a frame
+                    warn!("Stopped reading capture file {} at line {}: {}", path.display(), number + 1, e);
+                    return;
+                }
+            };
+
+            match ::serde_json::from_str::<CapturedFrame>(&line) {
+                Ok(frame) => self.replay_frame(frame),
+                Err(e) => warn!("Skipping invalid frame at line {} of {}: {}", number + 1, path.display(), e)
+            }
+        }
+    }
+
+    fn replay_frame(&mut self, frame: CapturedFrame) {
+        if !self.builders.contains_key(&frame.exchange) {
+            if self.skipped.insert(frame.exchange) {
+                warn!("Skipping recorded frames from {}, which is disabled", frame.exchange);
+            }
+            return;
+        }
+
+        self.wait_for(frame.ts);
+        self.frames += 1;
+        self.context.clock.set(frame.ts as Timestamp);
+
+        let replaying = self.connections.get(&frame.exchange).map(|&(connection, _)| connection);
+        if replaying != Some(frame.connection) {
+            // A new connection was made to the exchange, so the previous one must have been closed
+            if let Some((_, mut handler)) = self.connections.remove(&frame.exchange) {
+                handler.on_close(ws::CloseCode::Away, "recorded connection ended");
+            }
+
+            info!("Replaying recorded connection {} to {}", frame.connection, frame.exchange);
+            let mut inner = HandlerCore::detached(self.context.clone(), frame.exchange);
+            if let Err(e) = inner.connection_opened(frame.exchange) {
+                warn!("Could not broadcast {} open message: {}", frame.exchange, e);
+            }
+            let handler = (self.builders[&frame.exchange])(inner);
+            self.connections.insert(frame.exchange, (frame.connection, handler));
+        }
+
+        if let Some(&mut (_, ref mut handler)) = self.connections.get_mut(&frame.exchange) {
+            if let Err(e) = handler.on_message(ws::Message::text(frame.frame)) {
+                error!("Could not replay {} frame: {}", frame.exchange, e);
+            }
+        }
+    }
+
+    // Sleeps until the frame is due, relative to when the first frame was replayed
+    fn wait_for(&mut self, ts: u64) {
+        let speed = match self.config.speed {
+            Some(speed) => speed,
+            None => return
+        };
+
+        let (first_ts, started) = *self.started.get_or_insert_with(|| (ts, Instant::now()));
+        let due = Duration::from_millis((ts.saturating_sub(first_ts) as f64 / speed) as u64);
+        let elapsed = started.elapsed();
+        if due > elapsed {
+            thread::sleep(due - elapsed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitfinex::api::{Frequency, Precision};
+    use broadcast_api::{Broadcast, Stamped};
+    use config::{BookConfig, BookSubscription};
+    use std::sync::mpsc;
+
+    // Frames as Poloniex sends them on the BTC_XRP channel
+    const INITIAL: &str = r#"[148,534814,[["i",{"currencyPair":"BTC_XRP","orderBook":[{"0.00009000":"5.5"},{"0.00008900":"10"}]}]]]"#;
+    const CHANGES: &str = r#"[148,534815,[["o",1,"0.00008900","0.00000000"]]]"#;
+
+    fn pair() -> CurrencyPair {
+        CurrencyPair::new("XRP", "BTC")
+    }
+
+    fn replayer(speed: Option<f64>) -> (Replayer, mpsc::Receiver<Stamped>) {
+        let (context, broadcasts) = HandlerContext::detached();
+        let exchange = ExchangeConfig {
+            addr: ::url::Url::parse("wss://api2.poloniex.com").unwrap(),
+            required: false,
+            books: BookConfig {
+                default: BookSubscription { precision: Precision::R0, frequency: Frequency::F0, length: 100 },
+                pairs: HashMap::new()
+            }
+        };
+
+        let mut builders = HashMap::new();
+        builders.insert(Exchange::Poloniex, builder::<PoloniexFactory>(context.clone(), vec!(pair()), &exchange));
+        let replayer = Replayer {
+            context,
+            config: ReplayConfig { path: PathBuf::new(), speed },
+            builders,
+            connections: HashMap::new(),
+            skipped: HashSet::new(),
+            started: None,
+            frames: 0
+        };
+        (replayer, broadcasts)
+    }
+
+    #[test]
+    fn paces_frames_by_their_timestamps() {
+        let (mut replayer, _) = replayer(Some(10.0));
+        let started = Instant::now();
+        replayer.wait_for(1_600_000_000_000);
+        replayer.wait_for(1_600_000_000_500);
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(50) && elapsed < Duration::from_millis(500), "took {:?}", elapsed);
+
+        // Frames recorded out of order are replayed straight away
+        let started = Instant::now();
+        replayer.wait_for(1_600_000_000_000);
+        assert!(started.elapsed() < Duration::from_millis(50));
+    }
+
+    #[test]
+    fn replays_as_fast_as_possible_without_a_speed() {
+        let (mut replayer, _) = replayer(None);
+        let started = Instant::now();
+        replayer.wait_for(1_600_000_000_000);
+        replayer.wait_for(1_600_000_060_000);
+        assert!(started.elapsed() < Duration::from_millis(50));
+    }
+
+    #[test]
+    fn replays_recorded_frames_through_their_handlers() {
+        let dir = recorder::test_dir("replay-round-trip");
+        let frame = |ts, exchange, frame: &str| CapturedFrame { ts, exchange, connection: 3, frame: frame.to_string() };
+        recorder::write_capture(::config::RecorderConfig {
+            dir: dir.clone(),
+            rotate: Duration::from_secs(3600),
+            retention: Duration::from_secs(3600)
+        }, vec!(
+            frame(1_600_000_000_000, Exchange::Poloniex, INITIAL),
+            frame(1_600_000_000_100, Exchange::Bitfinex, "[17,\"hb\",5]"),
+            frame(1_600_000_000_200, Exchange::Poloniex, CHANGES)));
+
+        let (mut replayer, broadcasts) = replayer(None);
+        for path in capture_files(&dir).unwrap() {
+            replayer.replay_file(&path);
+        }
+        assert_eq!(replayer.frames, 2);
+        assert!(replayer.skipped.contains(&Exchange::Bitfinex));
+
+        // Broadcasts are stamped with the time their frame was recorded
+        let replayed: Vec<Stamped> = broadcasts.try_iter()
+            .filter(|(broadcast, _)| matches!(*broadcast, Broadcast::OrderbookSnapshot { .. } | Broadcast::OrderbookRemove { .. }))
+            .collect();
+        assert_eq!(replayed, vec!(
+            (Broadcast::OrderbookSnapshot {
+                source: Exchange::Poloniex,
+                pair: pair(),
+                bids: vec!((8900, 1_000_000_000)),
+                asks: vec!((9000, 550_000_000))
+            }, 1_600_000_000_000),
+            (Broadcast::OrderbookRemove { source: Exchange::Poloniex, pair: pair(), bids: vec!((8900, 0)), asks: vec!() }, 1_600_000_000_200)));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
\ No newline at end of file